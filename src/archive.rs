@@ -0,0 +1,257 @@
+use crate::object::Object;
+use byteorder::{BigEndian, ByteOrder};
+use std::collections::BTreeMap;
+
+/// The first bytes of every archive.
+const MAGIC: &[u8; 8] = b"!<arch>\n";
+
+/// The size of each member's header.
+const HEADER_SIZE: usize = 60;
+
+/// A collection of objects, of which the linker only uses those needed to
+/// resolve undefined symbols.
+///
+/// Archives are stored in the common (GNU) `ar` format, so they can be
+/// inspected using standard tools:
+///
+/// * A `/` member first, holding the symbol index: the number of symbols, then
+///   the offset of the header of the member defining each symbol (all
+///   big-endian 32-bit), followed by the null-terminated symbol names.
+/// * A `//` member, holding the names too long to fit in a header or not ASCII,
+///   each terminated by `/\n`.
+/// * A member for each object, serialized using [`Object::to_bytes`].
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Archive
+{
+	/// The name and contents of each member.
+	pub members: Vec<(String, Object)>,
+	/// Each global symbol, with the index of the first member defining it.
+	pub index: BTreeMap<String, usize>,
+}
+
+impl Archive
+{
+	/// Creates an archive of the given objects, indexing their global symbols.
+	pub fn new(members: Vec<(String, Object)>) -> Self
+	{
+		let mut index = BTreeMap::new();
+		for (idx, (_, object)) in members.iter().enumerate()
+		{
+			for label in object.exports()
+			{
+				index.entry(label.name.clone()).or_insert(idx);
+			}
+		}
+		Self { members, index }
+	}
+
+	/// Writes the archive in the `ar` format.
+	///
+	/// Fails if a member or the symbol index is too large for the format.
+	pub fn to_bytes(&self) -> Result<Vec<u8>, String>
+	{
+		let mut names = Vec::new();
+		let headers: Vec<_> = self
+			.members
+			.iter()
+			.map(|(name, _)| {
+				// Headers are padded by characters, which are only bytes if ASCII
+				if name.is_ascii() && name.len() < 16 && !name.contains('/')
+				{
+					format!("{}/", name)
+				}
+				else
+				{
+					let header = format!("/{}", names.len());
+					names.extend_from_slice(name.as_bytes());
+					names.extend_from_slice(b"/\n");
+					header
+				}
+			})
+			.collect();
+		let objects = self
+			.members
+			.iter()
+			.map(|(name, object)| {
+				object
+					.to_bytes()
+					.map_err(|err| format!("{}\nIn archive member '{}'", err, name))
+			})
+			.collect::<Result<Vec<_>, String>>()?;
+
+		let mut symbols = Vec::new();
+		for name in self.index.keys()
+		{
+			symbols.extend_from_slice(name.as_bytes());
+			symbols.push(0);
+		}
+		let index_size = 4 + 4 * self.index.len() + symbols.len();
+
+		// The offset of each member's header, for the symbol index
+		let mut offset = MAGIC.len() + HEADER_SIZE + padded(index_size);
+		if !names.is_empty()
+		{
+			offset += HEADER_SIZE + padded(names.len());
+		}
+		let mut offsets = Vec::new();
+		for object in &objects
+		{
+			offsets.push(
+				u32::try_from(offset)
+					.map_err(|_| format!("Member offset out of range for archive: {}", offset))?,
+			);
+			offset += HEADER_SIZE + padded(object.len());
+		}
+
+		let mut index = vec![0; 4 + 4 * self.index.len()];
+		let count = u32::try_from(self.index.len()).map_err(|_| {
+			format!("Symbol count out of range for archive: {}", self.index.len())
+		})?;
+		BigEndian::write_u32(&mut index, count);
+		for (idx, member) in self.index.values().enumerate()
+		{
+			BigEndian::write_u32(&mut index[4 + 4 * idx..], offsets[*member]);
+		}
+		index.extend(symbols);
+
+		let mut out = MAGIC.to_vec();
+		write_member(&mut out, "/", &index);
+		if !names.is_empty()
+		{
+			write_member(&mut out, "//", &names);
+		}
+		for (header, object) in headers.iter().zip(objects)
+		{
+			write_member(&mut out, header, &object);
+		}
+		Ok(out)
+	}
+
+	/// Reads an archive written by [`Archive::to_bytes`].
+	pub fn from_bytes(bytes: &[u8]) -> Result<Self, String>
+	{
+		if !bytes.starts_with(MAGIC)
+		{
+			return Err("Not an archive".to_owned());
+		}
+		let mut offset = MAGIC.len();
+		let mut members = Vec::new();
+		let mut offsets = Vec::new();
+		let mut symbols = None;
+		let mut names: &[u8] = &[];
+		while offset < bytes.len()
+		{
+			let header = bytes
+				.get(offset..offset + HEADER_SIZE)
+				.filter(|header| header.ends_with(b"`\n"))
+				.ok_or(format!("Invalid archive member header at {}", offset))?;
+			let name = std::str::from_utf8(&header[..16])
+				.map_err(|_| format!("Invalid archive member name at {}", offset))?
+				.trim_end();
+			let size: usize = std::str::from_utf8(&header[48..58])
+				.ok()
+				.and_then(|size| size.trim_end().parse().ok())
+				.ok_or(format!("Invalid archive member size at {}", offset))?;
+			let data = bytes
+				.get(offset + HEADER_SIZE..offset + HEADER_SIZE + size)
+				.ok_or(format!("Archive member at {} is truncated", offset))?;
+
+			match name
+			{
+				"/" => symbols = Some(data),
+				"//" => names = data,
+				_ =>
+				{
+					let name = if let Some(long) = name.strip_prefix('/')
+					{
+						long.parse::<usize>()
+							.ok()
+							.and_then(|start| names.get(start..))
+							.and_then(|rest| {
+								let end = rest.windows(2).position(|w| w == b"/\n")?;
+								std::str::from_utf8(&rest[..end]).ok()
+							})
+							.ok_or(format!("Invalid archive member name: {}", name))?
+					}
+					else
+					{
+						name.strip_suffix('/').unwrap_or(name)
+					};
+					let object = Object::from_bytes(data)
+						.map_err(|err| format!("{}\nIn archive member '{}'", err, name))?;
+					offsets.push(u32::try_from(offset).map_err(|_| {
+						format!("Member offset out of range for archive: {}", offset)
+					})?);
+					members.push((name.to_owned(), object));
+				},
+			}
+			offset += HEADER_SIZE + padded(size);
+		}
+
+		let mut archive = Self {
+			members,
+			index: BTreeMap::new(),
+		};
+		// Archives without a symbol index are indexed from their contents
+		let Some(symbols) = symbols
+		else
+		{
+			return Ok(Self::new(archive.members));
+		};
+		let invalid = || "Invalid archive symbol index".to_owned();
+		let count = symbols
+			.get(..4)
+			.map(BigEndian::read_u32)
+			.ok_or_else(invalid)? as usize;
+		let mut strings = symbols.get(4 + 4 * count..).ok_or_else(invalid)?;
+		for idx in 0..count
+		{
+			let member_offset = BigEndian::read_u32(&symbols[4 + 4 * idx..]);
+			let member = offsets
+				.iter()
+				.position(|o| *o == member_offset)
+				.ok_or_else(invalid)?;
+			let end = strings.iter().position(|b| *b == 0).ok_or_else(invalid)?;
+			let name = std::str::from_utf8(&strings[..end]).map_err(|_| invalid())?;
+			let (member_name, object) = &archive.members[member];
+			if !object.exports().any(|label| label.name == name)
+			{
+				return Err(format!(
+					"Invalid archive symbol index: '{}' doesn't define '{}'",
+					member_name, name
+				));
+			}
+			archive.index.insert(name.to_owned(), member);
+			strings = &strings[end + 1..];
+		}
+		Ok(archive)
+	}
+}
+
+/// Rounds the size of a member up to the 2-byte alignment of members.
+fn padded(size: usize) -> usize
+{
+	size + size % 2
+}
+
+/// Appends a member with the given header name and contents.
+fn write_member(out: &mut Vec<u8>, name: &str, data: &[u8])
+{
+	out.extend_from_slice(
+		format!(
+			"{:<16}{:<12}{:<6}{:<6}{:<8}{:<10}`\n",
+			name,
+			0,
+			0,
+			0,
+			644,
+			data.len()
+		)
+		.as_bytes(),
+	);
+	out.extend_from_slice(data);
+	if data.len() % 2 == 1
+	{
+		out.push(b'\n');
+	}
+}
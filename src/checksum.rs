@@ -0,0 +1,51 @@
+/// An algorithm for checksumming a range of assembled bytes.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum Checksum
+{
+	/// CRC-32 as used by Ethernet, zlib and PNG.
+	Crc32,
+	/// The sum of all bytes, modulo 256.
+	Sum8,
+}
+
+impl Checksum
+{
+	/// Returns the checksum the given directive calculates, if any.
+	pub fn from_directive(directive: &str) -> Option<Self>
+	{
+		match directive
+		{
+			".crc32" => Some(Self::Crc32),
+			".sum8" => Some(Self::Sum8),
+			_ => None,
+		}
+	}
+
+	/// The number of bytes the checksum takes up.
+	pub fn size(self) -> usize
+	{
+		match self
+		{
+			Self::Crc32 => 4,
+			Self::Sum8 => 1,
+		}
+	}
+
+	/// Calculates the checksum of the given bytes, in little-endian.
+	pub fn calculate(self, bytes: &[u8]) -> Vec<u8>
+	{
+		match self
+		{
+			Self::Crc32 =>
+			{
+				let crc = bytes.iter().fold(!0u32, |crc, byte| {
+					(0..8).fold(crc ^ *byte as u32, |crc, _| {
+						(crc >> 1) ^ (0xEDB8_8320 & (crc & 1).wrapping_neg())
+					})
+				});
+				(!crc).to_le_bytes().to_vec()
+			},
+			Self::Sum8 => vec![bytes.iter().fold(0u8, |sum, byte| sum.wrapping_add(*byte))],
+		}
+	}
+}
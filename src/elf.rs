@@ -0,0 +1,374 @@
+use crate::object::{Object, Reference, RelocationField, SymbolKind, Visibility};
+use byteorder::{ByteOrder, LittleEndian, WriteBytesExt};
+use std::collections::HashMap;
+
+/// The `e_machine` of ELF objects written by [`Object::to_elf`].
+///
+/// Scry has no registered machine number, so this placeholder ("SC" in ASCII)
+/// is used instead.
+/// It is far above the registered numbers, so tools show it as unknown rather
+/// than mistaking the objects for another architecture.
+pub const ELF_MACHINE: u16 = 0x5343;
+
+const SIZE_HEADER: usize = 52;
+const SIZE_SECTION_HEADER: u16 = 40;
+const SIZE_SYMBOL: u32 = 16;
+const SIZE_RELA: u32 = 12;
+
+/// The first reserved section index, above which sections can't be referred to
+/// without extended section numbering.
+const SHN_LORESERVE: usize = 0xff00;
+
+const SHT_PROGBITS: u32 = 1;
+const SHT_SYMTAB: u32 = 2;
+const SHT_STRTAB: u32 = 3;
+const SHT_RELA: u32 = 4;
+const SHT_NOBITS: u32 = 8;
+
+const SHF_WRITE: u32 = 0x1;
+const SHF_ALLOC: u32 = 0x2;
+const SHF_EXECINSTR: u32 = 0x4;
+const SHF_INFO_LINK: u32 = 0x40;
+
+const STB_LOCAL: u8 = 0;
+const STB_GLOBAL: u8 = 1;
+const STT_NOTYPE: u8 = 0;
+const STT_OBJECT: u8 = 1;
+const STT_FUNC: u8 = 2;
+const STT_SECTION: u8 = 3;
+
+/// A symbol in `.symtab`.
+struct Symbol
+{
+	name: u32,
+	value: u32,
+	size: u32,
+	info: u8,
+	/// The index of the section header of the section the symbol is in, or 0
+	/// if undefined.
+	section: u16,
+}
+
+impl Object
+{
+	/// Writes the object as a 32-bit little-endian ELF relocatable object, such
+	/// that it can be inspected by standard tools like `readelf` and `nm`.
+	///
+	/// Each section is given a section of the same name, followed by a `.rela`
+	/// section for each section with relocations, `.symtab`, `.strtab` and
+	/// `.shstrtab`.
+	/// `.symtab` starts with a symbol for each section, followed by the labels
+	/// and then the imports.
+	///
+	/// Relocations are at the offset of the instruction or data containing the
+	/// field.
+	/// References to labels in the object use the symbol of the label's
+	/// section, with the label's offset as the addend.
+	/// The bits of the relocation type describe the field and its value:
+	///
+	/// * Bits 0-2 are the field: 1 for an output offset, 2 for a jump target, 3
+	///   for a jump location, 4 for a `const` immediate, 5 for little-endian
+	///   bytes and 6 for big-endian bytes.
+	/// * Bits 3-4 are the value: 0 for `S + A`, 1 for `S + A - P`, or 2 and 3
+	///   for the first and second entries of a distance.
+	/// * Bits 5-7 are the base 2 logarithm of the number of bytes for bytes, or
+	///   0 otherwise.
+	///
+	/// A distance is given by two entries at the same offset: its value is
+	/// `S + A` of the first entry minus `S + A` of the second.
+	///
+	/// The information needed to patch instructions is not included, so the
+	/// object can't be linked by [`Linker`](crate::Linker) once converted.
+	///
+	/// Fails if the object has too many sections or symbols, or sizes, offsets
+	/// or addends that don't fit in their ELF fields.
+	pub fn to_elf(&self) -> Result<Vec<u8>, String>
+	{
+		let mut strtab = StringTable::default();
+		let mut symbols = vec![Symbol {
+			name: 0,
+			value: 0,
+			size: 0,
+			info: 0,
+			section: 0,
+		}];
+		// Section symbols, used for references to local labels
+		for idx in 0..self.sections.len()
+		{
+			symbols.push(Symbol {
+				name: 0,
+				value: 0,
+				size: 0,
+				info: STT_SECTION,
+				section: fit(idx + 1, "Section index")?,
+			});
+		}
+		// ELF requires local symbols to come before global ones
+		let (globals, locals): (Vec<_>, Vec<_>) = self
+			.labels
+			.iter()
+			.partition(|label| label.visibility == Visibility::Global);
+		let first_global = symbols.len() + locals.len();
+		let mut indices = HashMap::new();
+		for label in locals.into_iter().chain(globals)
+		{
+			let bind = match label.visibility
+			{
+				Visibility::Local => STB_LOCAL,
+				Visibility::Global => STB_GLOBAL,
+			};
+			let kind = match label.kind
+			{
+				SymbolKind::Unknown => STT_NOTYPE,
+				SymbolKind::Function => STT_FUNC,
+				SymbolKind::Object => STT_OBJECT,
+			};
+			if label.visibility == Visibility::Global
+			{
+				indices.insert(label.name.as_str(), symbols.len());
+			}
+			symbols.push(Symbol {
+				name: strtab.add(&label.name)?,
+				value: fit(label.offset, "Label offset")?,
+				size: fit(label.size.unwrap_or(0), "Label size")?,
+				info: bind << 4 | kind,
+				section: fit(label.section + 1, "Section index")?,
+			});
+		}
+		let imports = self.imports.iter().map(String::as_str);
+		let referenced = self.relocations.iter().flat_map(|relocation| {
+			let syms = relocation.reference.symbols().into_iter();
+			syms.zip(&relocation.targets)
+				.filter(|(_, target)| target.is_none())
+				.map(|(sym, _)| sym)
+		});
+		for sym in imports.chain(referenced)
+		{
+			if !indices.contains_key(sym)
+			{
+				indices.insert(sym, symbols.len());
+				symbols.push(Symbol {
+					name: strtab.add(sym)?,
+					value: 0,
+					size: 0,
+					info: STB_GLOBAL << 4 | STT_NOTYPE,
+					section: 0,
+				});
+			}
+		}
+
+		// The offset, info and addend of the entries of each section
+		let mut relas = vec![Vec::new(); self.sections.len()];
+		for relocation in &self.relocations
+		{
+			let field = match relocation.field
+			{
+				RelocationField::OutputOffset => 1,
+				RelocationField::JumpTarget => 2,
+				RelocationField::JumpLocation => 3,
+				RelocationField::ConstImmediate => 4,
+				RelocationField::Bytes { size, big_endian } =>
+				{
+					(5 + big_endian as u32) | size.trailing_zeros() << 5
+				},
+			};
+			// The kind of each entry, with the index of its symbol in the reference
+			let entries: &[(u32, usize)] = match relocation.reference
+			{
+				Reference::Address(_) => &[(0, 0)],
+				Reference::DistanceCurrent(_) => &[(1, 0)],
+				Reference::Distance(..) => &[(2, 1), (3, 0)],
+			};
+			let syms = relocation.reference.symbols();
+			for (kind, sym_idx) in entries
+			{
+				let target = relocation.targets.get(*sym_idx).copied().flatten();
+				let (symbol, addend): (usize, i32) = match target
+				{
+					Some((section, offset)) => (section + 1, fit(offset, "Relocation addend")?),
+					None => (indices[syms[*sym_idx]], 0),
+				};
+				// The symbol index has 24 bits
+				let symbol: u32 = fit(symbol, "Symbol index")?;
+				if symbol >= 1 << 24
+				{
+					return Err(format!("Symbol index out of range for ELF: {}", symbol));
+				}
+				let offset: u32 = fit(relocation.offset, "Relocation offset")?;
+				relas[relocation.section].push((offset, symbol << 8 | kind << 3 | field, addend));
+			}
+		}
+
+		let mut shstrtab = StringTable::default();
+		let mut out = vec![0; SIZE_HEADER];
+		// The name, type, flags, address, offset, size, link, info, alignment
+		// and entry size of each section
+		let mut headers = vec![[0u32; 10]];
+		for section in &self.sections
+		{
+			align(&mut out);
+			let offset: u32 = fit(out.len(), "File offset")?;
+			let mut flags = SHF_ALLOC;
+			if section.flags.write
+			{
+				flags |= SHF_WRITE;
+			}
+			if section.flags.execute
+			{
+				flags |= SHF_EXECINSTR;
+			}
+			let kind = if section.flags.no_bits
+			{
+				SHT_NOBITS
+			}
+			else
+			{
+				out.extend_from_slice(&section.bytes);
+				out.resize(offset as usize + section.size, 0);
+				SHT_PROGBITS
+			};
+			let name = shstrtab.add(&section.name)?;
+			headers.push([
+				name,
+				kind,
+				flags,
+				0,
+				offset,
+				fit(section.size, "Section size")?,
+				0,
+				0,
+				fit(section.alignment(), "Section alignment")?,
+				0,
+			]);
+		}
+
+		let symtab_idx = (headers.len() + relas.iter().filter(|r| !r.is_empty()).count()) as u32;
+		for (idx, entries) in relas.iter().enumerate().filter(|(_, r)| !r.is_empty())
+		{
+			align(&mut out);
+			let offset: u32 = fit(out.len(), "File offset")?;
+			for (offset, info, addend) in entries
+			{
+				out.write_u32::<LittleEndian>(*offset).unwrap();
+				out.write_u32::<LittleEndian>(*info).unwrap();
+				out.write_i32::<LittleEndian>(*addend).unwrap();
+			}
+			headers.push([
+				shstrtab.add(&format!(".rela{}", self.sections[idx].name))?,
+				SHT_RELA,
+				SHF_INFO_LINK,
+				0,
+				offset,
+				fit(entries.len() * SIZE_RELA as usize, "Relocations size")?,
+				symtab_idx,
+				idx as u32 + 1,
+				4,
+				SIZE_RELA,
+			]);
+		}
+
+		align(&mut out);
+		let offset: u32 = fit(out.len(), "File offset")?;
+		for symbol in &symbols
+		{
+			out.write_u32::<LittleEndian>(symbol.name).unwrap();
+			out.write_u32::<LittleEndian>(symbol.value).unwrap();
+			out.write_u32::<LittleEndian>(symbol.size).unwrap();
+			out.push(symbol.info);
+			out.push(0);
+			out.write_u16::<LittleEndian>(symbol.section).unwrap();
+		}
+		headers.push([
+			shstrtab.add(".symtab")?,
+			SHT_SYMTAB,
+			0,
+			0,
+			offset,
+			fit(symbols.len() * SIZE_SYMBOL as usize, "Symbol table size")?,
+			symtab_idx + 1,
+			fit(first_global, "Symbol index")?,
+			4,
+			SIZE_SYMBOL,
+		]);
+		let name = shstrtab.add(".strtab")?;
+		headers.push([
+			name,
+			SHT_STRTAB,
+			0,
+			0,
+			fit(out.len(), "File offset")?,
+			fit(strtab.0.len(), "String table size")?,
+			0,
+			0,
+			1,
+			0,
+		]);
+		out.extend_from_slice(&strtab.0);
+		let name = shstrtab.add(".shstrtab")?;
+		let size: u32 = fit(shstrtab.0.len(), "String table size")?;
+		let offset: u32 = fit(out.len(), "File offset")?;
+		headers.push([name, SHT_STRTAB, 0, 0, offset, size, 0, 0, 1, 0]);
+		out.extend_from_slice(&shstrtab.0);
+
+		align(&mut out);
+		let headers_offset: u32 = fit(out.len(), "File offset")?;
+		for field in headers.iter().flatten()
+		{
+			out.write_u32::<LittleEndian>(*field).unwrap();
+		}
+
+		if headers.len() >= SHN_LORESERVE
+		{
+			return Err(format!("Too many sections for ELF: {}", headers.len()));
+		}
+		let header = &mut out[..SIZE_HEADER];
+		// Magic, 32-bit, little-endian, version 1
+		header[..7].copy_from_slice(b"\x7fELF\x01\x01\x01");
+		// Relocatable
+		LittleEndian::write_u16(&mut header[16..], 1);
+		LittleEndian::write_u16(&mut header[18..], ELF_MACHINE);
+		LittleEndian::write_u32(&mut header[20..], 1);
+		LittleEndian::write_u32(&mut header[32..], headers_offset);
+		LittleEndian::write_u16(&mut header[40..], SIZE_HEADER as u16);
+		LittleEndian::write_u16(&mut header[46..], SIZE_SECTION_HEADER);
+		LittleEndian::write_u16(&mut header[48..], headers.len() as u16);
+		LittleEndian::write_u16(&mut header[50..], headers.len() as u16 - 1);
+		Ok(out)
+	}
+}
+
+/// Converts the number to the type of its ELF field, failing if it doesn't fit.
+fn fit<T: TryFrom<usize>>(value: usize, name: &str) -> Result<T, String>
+{
+	T::try_from(value).map_err(|_| format!("{} out of range for ELF: {}", name, value))
+}
+
+/// Pads the output to a multiple of 4 bytes.
+fn align(out: &mut Vec<u8>)
+{
+	out.resize(out.len().next_multiple_of(4), 0);
+}
+
+/// The contents of a string table.
+struct StringTable(Vec<u8>);
+
+impl Default for StringTable
+{
+	fn default() -> Self
+	{
+		// Offset 0 is the empty string
+		Self(vec![0])
+	}
+}
+
+impl StringTable
+{
+	/// Adds the string, returning its offset.
+	fn add(&mut self, string: &str) -> Result<u32, String>
+	{
+		let offset: u32 = fit(self.0.len(), "String table offset")?;
+		self.0.extend_from_slice(string.as_bytes());
+		self.0.push(0);
+		Ok(offset)
+	}
+}
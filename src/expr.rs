@@ -0,0 +1,502 @@
+use crate::lexer::{Cursor, Token};
+use std::fmt::{Display, Formatter};
+
+/// Prefix of the symbols that replace expressions in braces.
+const EXPRESSION_SYMBOL: &str = "__expr_";
+
+/// An operator taking one operand.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum UnaryOp
+{
+	/// `-`
+	Neg,
+	/// `~`
+	BitNot,
+	/// `!`
+	Not,
+}
+
+/// An operator taking two operands.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum BinaryOp
+{
+	Mul,
+	Div,
+	Rem,
+	Add,
+	Sub,
+	Shl,
+	Shr,
+	Lt,
+	Le,
+	Gt,
+	Ge,
+	Eq,
+	Ne,
+	BitAnd,
+	BitXor,
+	BitOr,
+	And,
+	Or,
+}
+
+/// All binary operators with their precedence (higher binds tighter).
+///
+/// Longer operators come before any operator that is a prefix of them.
+const BINARY_OPS: &[(&str, BinaryOp, u8)] = &[
+	("||", BinaryOp::Or, 1),
+	("&&", BinaryOp::And, 2),
+	("==", BinaryOp::Eq, 6),
+	("!=", BinaryOp::Ne, 6),
+	("<=", BinaryOp::Le, 7),
+	(">=", BinaryOp::Ge, 7),
+	("<<", BinaryOp::Shl, 8),
+	(">>", BinaryOp::Shr, 8),
+	("|", BinaryOp::BitOr, 3),
+	("^", BinaryOp::BitXor, 4),
+	("&", BinaryOp::BitAnd, 5),
+	("<", BinaryOp::Lt, 7),
+	(">", BinaryOp::Gt, 7),
+	("+", BinaryOp::Add, 9),
+	("-", BinaryOp::Sub, 9),
+	("*", BinaryOp::Mul, 10),
+	("/", BinaryOp::Div, 10),
+	("%", BinaryOp::Rem, 10),
+];
+
+/// An expression that evaluates to an integer at assembly time.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) enum Expr
+{
+	Number(i128),
+	Symbol(String),
+	Unary(UnaryOp, Box<Expr>),
+	Binary(BinaryOp, Box<Expr>, Box<Expr>),
+	/// Bits `lo` through `hi` (inclusive) of the expression's value, as
+	/// extracted by `%lo8`, `%hi8` and `%bits`.
+	Bits(Box<Expr>, u32, u32),
+}
+
+impl Expr
+{
+	/// Parses an expression at the cursor.
+	///
+	/// The expression ends at the first token that cannot continue it, so
+	/// expressions don't need to be terminated.
+	/// Operators have the same meaning and precedence as in C.
+	/// `.` and `$` both parse as the symbol `.`, which is the current address.
+	/// `%lo8(x)`, `%hi8(x)` and `%bits(x, lo, hi)` extract bits 0-7, 8-15 and
+	/// `lo`-`hi` of `x`, respectively.
+	pub fn parse(cursor: &mut Cursor) -> Result<Self, String>
+	{
+		Self::parse_binary(cursor, 0)
+	}
+
+	fn parse_binary(cursor: &mut Cursor, min_precedence: u8) -> Result<Self, String>
+	{
+		let mut lhs = Self::parse_unary(cursor)?;
+		while let Some((op, len, precedence)) = cursor.current().and_then(|tok| {
+			BINARY_OPS
+				.iter()
+				.find(|(symbol, ..)| tok.starts_with(symbol))
+				.map(|(symbol, op, precedence)| (*op, symbol.len(), *precedence))
+		})
+		{
+			if precedence < min_precedence
+			{
+				break;
+			}
+			cursor.bump(len);
+			let rhs = Self::parse_binary(cursor, precedence + 1)?;
+			lhs = Expr::Binary(op, Box::new(lhs), Box::new(rhs));
+		}
+		Ok(lhs)
+	}
+
+	fn parse_unary(cursor: &mut Cursor) -> Result<Self, String>
+	{
+		let op = if cursor.eat("-")
+		{
+			UnaryOp::Neg
+		}
+		else if cursor.eat("~")
+		{
+			UnaryOp::BitNot
+		}
+		else if cursor.eat("!")
+		{
+			UnaryOp::Not
+		}
+		else
+		{
+			return Self::parse_atom(cursor);
+		};
+		Ok(Expr::Unary(op, Box::new(Self::parse_unary(cursor)?)))
+	}
+
+	fn parse_atom(cursor: &mut Cursor) -> Result<Self, String>
+	{
+		let tok = cursor.current().unwrap_or("");
+		if tok.starts_with('%')
+		{
+			Self::parse_bits(cursor)
+		}
+		else if cursor.eat("(")
+		{
+			let expr = Self::parse(cursor)?;
+			if !cursor.eat(")")
+			{
+				return Err(format!("Expected ')': {}", cursor.current().unwrap_or("")));
+			}
+			Ok(expr)
+		}
+		else if tok.starts_with('$')
+			|| (tok.starts_with('.')
+				&& !tok[1..].starts_with(|c: char| c.is_alphanumeric() || c == '_'))
+		{
+			cursor.bump(1);
+			Ok(Expr::Symbol(".".to_owned()))
+		}
+		else
+		{
+			// Symbols may contain '.' to refer to local labels
+			let len = tok
+				.find(|c: char| !(c.is_alphanumeric() || c == '_' || c == '.'))
+				.unwrap_or(tok.len());
+			let word = &tok[..len];
+			// Numbers followed by 'f' or 'b' refer to numeric labels
+			let is_numeric_label = word
+				.strip_suffix(['f', 'b'])
+				.is_some_and(|n| !n.is_empty() && n.chars().all(|c| c.is_ascii_digit()));
+			if word.starts_with(|c: char| c.is_ascii_digit()) && !is_numeric_label
+			{
+				let digits = word.find(|c: char| !c.is_ascii_digit()).unwrap_or(len);
+				let value = word[..digits]
+					.parse::<u128>()
+					.ok()
+					.and_then(|value| i128::try_from(value).ok())
+					.ok_or(format!("Number out of range: {}", word))?;
+				cursor.bump(digits);
+				return Ok(Expr::Number(value));
+			}
+			if len == 0
+			{
+				return Err(format!("Expected expression: {}", tok));
+			}
+			cursor.bump(len);
+			Ok(Expr::Symbol(word.to_owned()))
+		}
+	}
+
+	/// Parses `%lo8(x)`, `%hi8(x)` or `%bits(x, lo, hi)`.
+	fn parse_bits(cursor: &mut Cursor) -> Result<Self, String>
+	{
+		let tok = cursor.current().unwrap_or("");
+		cursor.bump(1);
+		let function = cursor.identifier()?;
+		if !cursor.eat("(")
+		{
+			return Err(format!("Expected '(': {}", cursor.current().unwrap_or("")));
+		}
+		let operand = Self::parse(cursor)?;
+		let (lo, hi) = match function
+		{
+			"lo8" => (0, 7),
+			"hi8" => (8, 15),
+			"bits" =>
+			{
+				let mut bounds = [0; 2];
+				for bound in bounds.iter_mut()
+				{
+					if !cursor.eat(",")
+					{
+						return Err(format!("Expected ',': {}", cursor.current().unwrap_or("")));
+					}
+					*bound = cursor.integer()?;
+				}
+				if bounds[0] > bounds[1] || bounds[1] >= 128
+				{
+					return Err(format!("Invalid bit range: {}", tok));
+				}
+				(bounds[0] as u32, bounds[1] as u32)
+			},
+			_ => return Err(format!("Unknown operator: %{}", function)),
+		};
+		if !cursor.eat(")")
+		{
+			return Err(format!("Expected ')': {}", cursor.current().unwrap_or("")));
+		}
+		Ok(Expr::Bits(Box::new(operand), lo, hi))
+	}
+
+	/// Returns all bit extractions in the expression.
+	pub fn bits(&self) -> Vec<(&Expr, u32, u32)>
+	{
+		match self
+		{
+			Expr::Number(_) | Expr::Symbol(_) => Vec::new(),
+			Expr::Unary(_, operand) => operand.bits(),
+			Expr::Binary(_, lhs, rhs) => [lhs.bits(), rhs.bits()].concat(),
+			Expr::Bits(operand, lo, hi) =>
+			{
+				let mut bits = operand.bits();
+				bits.push((operand, *lo, *hi));
+				bits
+			},
+		}
+	}
+
+	/// Returns all symbols in the expression, in order.
+	pub fn symbols(&self) -> Vec<&str>
+	{
+		match self
+		{
+			Expr::Number(_) => Vec::new(),
+			Expr::Symbol(sym) => vec![sym],
+			Expr::Unary(_, operand) | Expr::Bits(operand, ..) => operand.symbols(),
+			Expr::Binary(_, lhs, rhs) => [lhs.symbols(), rhs.symbols()].concat(),
+		}
+	}
+
+	/// Evaluates the expression, using the given function to get the value of
+	/// symbols.
+	pub fn evaluate<F>(&self, symbol: &F) -> Result<i128, String>
+	where
+		F: Fn(&str) -> Result<i128, String>,
+	{
+		match self
+		{
+			Expr::Number(value) => Ok(*value),
+			Expr::Symbol(sym) => symbol(sym),
+			Expr::Unary(op, operand) =>
+			{
+				let value = operand.evaluate(symbol)?;
+				match op
+				{
+					UnaryOp::Neg => value.checked_neg().ok_or(format!("Overflow: {}", self)),
+					UnaryOp::BitNot => Ok(!value),
+					UnaryOp::Not => Ok((value == 0) as i128),
+				}
+			},
+			Expr::Binary(op, lhs, rhs) =>
+			{
+				let lhs = lhs.evaluate(symbol)?;
+				let rhs = rhs.evaluate(symbol)?;
+				let shift = || u32::try_from(rhs).ok().filter(|rhs| *rhs < 128);
+				match op
+				{
+					BinaryOp::Div | BinaryOp::Rem if rhs == 0 =>
+					{
+						return Err(format!("Division by zero: {}", self))
+					},
+					BinaryOp::Shl | BinaryOp::Shr if shift().is_none() =>
+					{
+						return Err(format!("Invalid shift amount: {}", self))
+					},
+					_ => (),
+				}
+				match op
+				{
+					BinaryOp::Mul => lhs.checked_mul(rhs),
+					BinaryOp::Div => lhs.checked_div(rhs),
+					BinaryOp::Rem => lhs.checked_rem(rhs),
+					BinaryOp::Add => lhs.checked_add(rhs),
+					BinaryOp::Sub => lhs.checked_sub(rhs),
+					BinaryOp::Shl =>
+					{
+						// Shifting must not lose any bits
+						lhs.checked_shl(shift().unwrap())
+							.filter(|result| result >> shift().unwrap() == lhs)
+					},
+					BinaryOp::Shr => Some(lhs >> shift().unwrap()),
+					BinaryOp::Lt => Some((lhs < rhs) as i128),
+					BinaryOp::Le => Some((lhs <= rhs) as i128),
+					BinaryOp::Gt => Some((lhs > rhs) as i128),
+					BinaryOp::Ge => Some((lhs >= rhs) as i128),
+					BinaryOp::Eq => Some((lhs == rhs) as i128),
+					BinaryOp::Ne => Some((lhs != rhs) as i128),
+					BinaryOp::BitAnd => Some(lhs & rhs),
+					BinaryOp::BitXor => Some(lhs ^ rhs),
+					BinaryOp::BitOr => Some(lhs | rhs),
+					BinaryOp::And => Some((lhs != 0 && rhs != 0) as i128),
+					BinaryOp::Or => Some((lhs != 0 || rhs != 0) as i128),
+				}
+				.ok_or(format!("Overflow: {}", self))
+			},
+			Expr::Bits(operand, lo, hi) =>
+			{
+				let value = operand.evaluate(symbol)?;
+				Ok((value >> lo) & bit_mask(hi - lo + 1) as i128)
+			},
+		}
+	}
+}
+
+impl Display for Expr
+{
+	fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result
+	{
+		match self
+		{
+			Expr::Number(value) => write!(f, "{}", value),
+			Expr::Symbol(sym) => f.write_str(sym),
+			Expr::Unary(op, operand) =>
+			{
+				let op = match op
+				{
+					UnaryOp::Neg => "-",
+					UnaryOp::BitNot => "~",
+					UnaryOp::Not => "!",
+				};
+				write!(f, "{}{}", op, operand)
+			},
+			Expr::Binary(op, lhs, rhs) =>
+			{
+				let (symbol, ..) = BINARY_OPS.iter().find(|(_, o, _)| o == op).unwrap();
+				write!(f, "({} {} {})", lhs, symbol, rhs)
+			},
+			Expr::Bits(operand, 0, 7) => write!(f, "%lo8({})", operand),
+			Expr::Bits(operand, 8, 15) => write!(f, "%hi8({})", operand),
+			Expr::Bits(operand, lo, hi) => write!(f, "%bits({}, {}, {})", operand, lo, hi),
+		}
+	}
+}
+
+/// Returns a mask of the lowest `count` bits.
+pub(crate) fn bit_mask(count: u32) -> u128
+{
+	u128::MAX.checked_shr(128 - count).unwrap_or(0)
+}
+
+/// Whether the given value can be reconstructed from the bits in the mask,
+/// either as an unsigned value or, if the bits start at bit 0 and are
+/// contiguous, as a signed value.
+pub(crate) fn is_covered(value: i128, mask: u128) -> bool
+{
+	if value >= 0
+	{
+		value as u128 & !mask == 0
+	}
+	else
+	{
+		let count = mask.trailing_ones();
+		count == 128 || (count > 0 && value >= -(1i128 << (count - 1)))
+	}
+}
+
+/// Replaces all expressions in braces (`{...}`) with symbols, such that they
+/// can be used wherever `scry_isa` expects a symbol.
+///
+/// `.` and `$` used as operands outside braces are replaced the same way, as
+/// if written `{.}`.
+///
+/// Returns the new tokens, adding the extracted expressions to `expressions`
+/// in order.
+/// Use [`expression_index`] to get the index of the expression a symbol
+/// replaced.
+pub(crate) fn extract_expressions(
+	mut tokens: Vec<Token>,
+	expressions: &mut Vec<Expr>,
+) -> Result<Vec<Token>, String>
+{
+	let mut idx = 0;
+	while let Some(token) = tokens.get(idx)
+	{
+		// Braces in strings aren't expressions
+		let start = match token.text.find('{')
+		{
+			Some(start) if !token.text.starts_with('"') => start,
+			_ =>
+			{
+				idx += 1;
+				continue;
+			},
+		};
+
+		let mut cursor = Cursor::new(&tokens[idx..]);
+		cursor.bump(start + 1);
+		let expr = Expr::parse(&mut cursor)?;
+		if !cursor.eat("}")
+		{
+			return Err(format!(
+				"Expected '}}' after expression: {}",
+				cursor.current().unwrap_or("")
+			));
+		}
+
+		// Replace the expression with its symbol, keeping anything around it
+		let mut text = format!(
+			"{}{}{}",
+			&token.text[..start],
+			EXPRESSION_SYMBOL,
+			expressions.len()
+		);
+		let mut end = idx + cursor.pos.idx;
+		if cursor.pos.offset > 0
+		{
+			text.push_str(&tokens[end].text[cursor.pos.offset..]);
+			end += 1;
+		}
+		let expansion = token.expansion;
+		expressions.push(expr);
+		// The rest of the new token may contain more expressions, so it is
+		// checked again
+		tokens.splice(idx..end, [Token { text, expansion }]);
+	}
+	for token in tokens.iter_mut()
+	{
+		if let Some(idx) = location_operand(&token.text)
+		{
+			token.text = format!(
+				"{}{}{}{}",
+				&token.text[..idx],
+				EXPRESSION_SYMBOL,
+				expressions.len(),
+				&token.text[idx + 1..]
+			);
+			expressions.push(Expr::Symbol(".".to_owned()));
+		}
+	}
+	Ok(tokens)
+}
+
+/// If the token is an operand that is just `.` or `$`, optionally after `=>`
+/// or before `,`, returns the index of the `.` or `$`.
+fn location_operand(token: &str) -> Option<usize>
+{
+	let operand = token.strip_suffix(',').unwrap_or(token);
+	let prefix = operand
+		.strip_suffix('.')
+		.or_else(|| operand.strip_suffix('$'))?;
+	(prefix.is_empty() || prefix.ends_with("=>")).then_some(prefix.len())
+}
+
+/// If the given symbol replaced an expression, returns the expression's index.
+pub(crate) fn expression_index(sym: &str) -> Option<usize>
+{
+	sym.strip_prefix(EXPRESSION_SYMBOL)?.parse().ok()
+}
+
+/// Replaces all expression symbols in the given text with the expressions
+/// they replaced, for showing the text to users.
+pub(crate) fn restore_expressions(text: &str, expressions: &[Expr]) -> String
+{
+	let mut result = String::new();
+	let mut rest = text;
+	while let Some(start) = rest.find(EXPRESSION_SYMBOL)
+	{
+		let digits = rest[start + EXPRESSION_SYMBOL.len()..]
+			.find(|c: char| !c.is_ascii_digit())
+			.unwrap_or(rest.len() - start - EXPRESSION_SYMBOL.len());
+		let end = start + EXPRESSION_SYMBOL.len() + digits;
+		result.push_str(&rest[..start]);
+		match expression_index(&rest[start..end]).and_then(|idx| expressions.get(idx))
+		{
+			Some(expr) => result.push_str(&format!("{{{}}}", expr)),
+			None => result.push_str(&rest[start..end]),
+		}
+		rest = &rest[end..];
+	}
+	result.push_str(rest);
+	result
+}
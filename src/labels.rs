@@ -0,0 +1,350 @@
+use crate::lexer::{quoted_len, Cursor, Token};
+
+/// Prefix of the symbols that replace scoped labels.
+const LABEL_SYMBOL: &str = "__label_";
+
+/// Returns the label declared by the token at the given index, if any.
+///
+/// Labels are declared by `label:` and by `.func label`.
+fn declared_label(tokens: &[Token], idx: usize) -> Option<&str>
+{
+	let text = tokens[idx].as_str();
+	if text == ".func"
+	{
+		return tokens.get(idx + 1).map(Token::as_str);
+	}
+	text.strip_suffix(':')
+		.or(tokens
+			.get(idx + 1)
+			.filter(|t| t.as_str() == ":")
+			.map(|_| text))
+		.filter(|label| !label.is_empty())
+}
+
+/// Whether the character can be part of a label's name.
+fn is_label_char(c: char) -> bool
+{
+	c.is_alphanumeric() || c == '_' || c == '.'
+}
+
+/// Words used by directives, other than types.
+const DIRECTIVE_KEYWORDS: [&str; 4] = ["big", "little", "function", "object"];
+
+/// Whether the word is an instruction mnemonic, a type, like `u8`, or a
+/// directive keyword, which a private label must not be named, as all uses of
+/// its name are replaced.
+fn is_keyword(word: &str) -> bool
+{
+	let is_type = word
+		.strip_prefix(['u', 'i'])
+		.is_some_and(|bits| !bits.is_empty() && bits.chars().all(|c| c.is_ascii_digit()));
+	scry_isa::INSTRUCTION_MNEMONICS.contains(&word)
+		|| DIRECTIVE_KEYWORDS.contains(&word)
+		|| is_type
+}
+
+/// Returns the names given to sections using `.section` in the given tokens.
+fn section_names(tokens: &[Token]) -> Vec<&str>
+{
+	tokens
+		.windows(2)
+		.filter(|pair| pair[0].as_str() == ".section")
+		.map(|pair| {
+			let tok = pair[1].as_str();
+			&tok[..tok.find(|c| !is_label_char(c)).unwrap_or(tok.len())]
+		})
+		.collect()
+}
+
+/// Whether the label is a numeric label, like `1`.
+fn is_numeric(label: &str) -> bool
+{
+	label.chars().all(|c| c.is_ascii_digit())
+}
+
+/// Resolves local and numeric labels.
+///
+/// Local labels (starting with `.`) are scoped to the most recent non-local,
+/// non-numeric label before them.
+/// A local label `.loop` declared after the label `func` is named `func.loop`.
+/// Within the scope of `func`, it can be referred to as both `.loop` and
+/// `func.loop`, while elsewhere only `func.loop` can be used.
+///
+/// Numeric labels (e.g. `1`) can be declared any number of times.
+/// `1f` refers to the next declaration of `1`, while `1b` refers to the
+/// previous one.
+///
+/// If `private` is set, all other labels not declared `.global` are also
+/// scoped to the given tokens, such that other files can declare labels with
+/// the same names.
+/// As labels are replaced wherever their names appear, such labels must not be
+/// named like instructions, types or directive keywords.
+/// For the same reason, no replaced label may be named like a section.
+///
+/// All declarations and references are replaced by symbols, such that
+/// `scry_isa` accepts them.
+/// The full names of the labels are added to `names`, in order.
+/// Use [`restore_labels`] to get the full names back.
+pub(crate) fn scope_labels(
+	mut tokens: Vec<Token>,
+	names: &mut Vec<String>,
+	private: bool,
+) -> Result<Vec<Token>, String>
+{
+	// Labels replaced by an earlier call can't be referred to from these tokens
+	let first = names.len();
+	let globals = if private
+	{
+		global_symbols(&tokens)
+	}
+	else
+	{
+		Vec::new()
+	};
+
+	// First find all scoped labels, so we know which references to replace
+	// The token index, number and symbol index of each numeric label
+	let mut numeric = Vec::new();
+	let mut scope = "";
+	let sections = section_names(&tokens);
+	for idx in 0..tokens.len()
+	{
+		match declared_label(&tokens, idx)
+		{
+			Some(label) if label.starts_with('.') =>
+			{
+				if sections.contains(&label)
+				{
+					return Err(format!("Label named like a section: {}", label));
+				}
+				let name = format!("{}{}", scope, label);
+				if !names[first..].contains(&name)
+				{
+					names.push(name);
+				}
+			},
+			Some(label) if is_numeric(label) =>
+			{
+				numeric.push((idx, label.to_owned(), names.len()));
+				names.push(label.to_owned());
+			},
+			Some(label) =>
+			{
+				scope = label;
+				let name = label.to_owned();
+				if private && !globals.contains(&name)
+				{
+					if is_keyword(label)
+					{
+						return Err(format!("Private label named like a keyword: {}", label));
+					}
+					if sections.contains(&label)
+					{
+						return Err(format!("Label named like a section: {}", label));
+					}
+					if !names[first..].contains(&name)
+					{
+						names.push(name);
+					}
+				}
+			},
+			None => (),
+		}
+	}
+	if names.len() == first
+	{
+		return Ok(tokens);
+	}
+
+	let mut scope = String::new();
+	for idx in 0..tokens.len()
+	{
+		let declared = declared_label(&tokens, idx).map(str::to_owned);
+		if let Some((.., symbol)) = numeric.iter().find(|(decl, ..)| *decl == idx)
+		{
+			// Keep the ':' if it is part of the token
+			let colon = &tokens[idx].text[declared.unwrap().len()..];
+			tokens[idx].text = format!("{}{}{}", LABEL_SYMBOL, symbol, colon);
+			continue;
+		}
+		let find_numeric = |number: &str, forward| {
+			let mut candidates = numeric.iter().filter(|(_, n, _)| n == number);
+			if forward
+			{
+				candidates.find(|(decl, ..)| *decl > idx)
+			}
+			else
+			{
+				candidates.rfind(|(decl, ..)| *decl < idx)
+			}
+			.map(|(.., symbol)| *symbol)
+		};
+		let named = |full: &str| {
+			names[first..]
+				.iter()
+				.position(|name| name == full)
+				.map(|idx| first + idx)
+		};
+		tokens[idx].text = replace_labels(tokens[idx].as_str(), &scope, named, find_numeric);
+		if let Some(global) = declared.filter(|label| !label.starts_with('.'))
+		{
+			scope = global;
+		}
+	}
+	Ok(tokens)
+}
+
+/// Returns the symbols declared using `.global` in the given tokens.
+fn global_symbols(tokens: &[Token]) -> Vec<String>
+{
+	let mut globals = Vec::new();
+	for idx in 0..tokens.len()
+	{
+		if tokens[idx].as_str() != ".global"
+		{
+			continue;
+		}
+		let mut cursor = Cursor::new(&tokens[idx + 1..]);
+		// Local labels can be global too, so their full names are used
+		while let Some(symbol) = cursor
+			.current()
+			.map(|tok| &tok[..tok.find(|c| !is_label_char(c)).unwrap_or(tok.len())])
+			.filter(|symbol| !symbol.is_empty())
+		{
+			globals.push(symbol.to_owned());
+			cursor.bump(symbol.len());
+			if !cursor.eat(",")
+			{
+				break;
+			}
+		}
+	}
+	globals
+}
+
+/// Replaces all references to scoped labels in the text with their symbols.
+///
+/// `named` gives the symbol index of the label with the given full name.
+/// `numeric` gives the symbol index of the numeric label with the given
+/// number, in the forward (`true`) or backward direction.
+fn replace_labels<N, F>(text: &str, scope: &str, named: N, numeric: F) -> String
+where
+	N: Fn(&str) -> Option<usize>,
+	F: Fn(&str, bool) -> Option<usize>,
+{
+	let mut result = String::with_capacity(text.len());
+	let mut rest = text;
+	while let Some(c) = rest.chars().next()
+	{
+		let len = if c == '"' || c == '\''
+		{
+			quoted_len(rest)
+		}
+		else if is_label_char(c)
+		{
+			let len = rest.find(|c| !is_label_char(c)).unwrap_or(rest.len());
+			let word = &rest[..len];
+			let full = if word.starts_with('.')
+			{
+				format!("{}{}", scope, word)
+			}
+			else
+			{
+				word.to_owned()
+			};
+			let number = |suffix| {
+				word.strip_suffix(suffix)
+					.filter(|n| !n.is_empty() && is_numeric(n))
+			};
+			let symbol = if let Some(number) = number('f')
+			{
+				numeric(number, true)
+			}
+			else if let Some(number) = number('b')
+			{
+				numeric(number, false)
+			}
+			else if is_numeric(word)
+			{
+				// Numeric labels are only referred to with 'f' or 'b'
+				None
+			}
+			else
+			{
+				named(&full)
+			};
+			if let Some(idx) = symbol
+			{
+				result.push_str(&format!("{}{}", LABEL_SYMBOL, idx));
+				rest = &rest[len..];
+				continue;
+			}
+			len
+		}
+		else
+		{
+			c.len_utf8()
+		};
+		result.push_str(&rest[..len]);
+		rest = &rest[len..];
+	}
+	result
+}
+
+/// Replaces all label symbols in the given text with the full names of the
+/// labels, for showing the text to users.
+pub(crate) fn restore_labels(text: &str, names: &[String]) -> String
+{
+	replace_symbols(text, |idx| names.get(idx).cloned())
+}
+
+/// Replaces all label symbols in the given text with references to the labels
+/// as they could be written in the source.
+///
+/// Unlike in [`restore_labels`], numeric labels are named by the references
+/// `1f` or `1b`, as their names alone are ambiguous.
+/// `declared` tells whether the label with the given symbol has been declared
+/// before the text.
+pub(crate) fn restore_references(
+	text: &str,
+	names: &[String],
+	declared: impl Fn(&str) -> bool,
+) -> String
+{
+	replace_symbols(text, |idx| {
+		let name = names.get(idx)?;
+		if is_numeric(name)
+		{
+			let backward = declared(&format!("{}{}", LABEL_SYMBOL, idx));
+			Some(format!("{}{}", name, if backward { 'b' } else { 'f' }))
+		}
+		else
+		{
+			Some(name.clone())
+		}
+	})
+}
+
+/// Replaces all label symbols in the given text with what `name` gives for
+/// the index of their label, if anything.
+fn replace_symbols(text: &str, name: impl Fn(usize) -> Option<String>) -> String
+{
+	let mut result = String::new();
+	let mut rest = text;
+	while let Some(start) = rest.find(LABEL_SYMBOL)
+	{
+		let digits = rest[start + LABEL_SYMBOL.len()..]
+			.find(|c: char| !c.is_ascii_digit())
+			.unwrap_or(rest.len() - start - LABEL_SYMBOL.len());
+		let end = start + LABEL_SYMBOL.len() + digits;
+		result.push_str(&rest[..start]);
+		let name = rest[start + LABEL_SYMBOL.len()..end]
+			.parse::<usize>()
+			.ok()
+			.and_then(&name);
+		result.push_str(name.as_deref().unwrap_or(&rest[start..end]));
+		rest = &rest[end..];
+	}
+	result.push_str(rest);
+	result
+}
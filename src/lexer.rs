@@ -0,0 +1,421 @@
+use scry_isa::CanConsume;
+use std::{iter::Map, slice::Iter};
+
+/// Splits assembly into tokens.
+///
+/// Comments (from `;` to the end of the line) and whitespace are removed.
+/// String and character literals are kept whole, including their quotes, even
+/// if they contain whitespace, `;` or `:`.
+/// All tokens are split after `:`, so we can recognize label declarations.
+pub(crate) fn tokenize<'a>(asm: impl Iterator<Item = &'a str>) -> Vec<&'a str>
+{
+	let mut tokens = Vec::new();
+	for chunk in asm
+	{
+		let mut start = None;
+		let mut chars = chunk.char_indices();
+		while let Some((idx, c)) = chars.next()
+		{
+			match c
+			{
+				';' =>
+				{
+					tokens.extend(start.take().map(|s| &chunk[s..idx]));
+					// Skip until the end of the line
+					for (_, c) in chars.by_ref()
+					{
+						if c == '\r' || c == '\n'
+						{
+							break;
+						}
+					}
+				},
+				':' =>
+				{
+					tokens.push(&chunk[start.take().unwrap_or(idx)..=idx]);
+				},
+				quote @ ('"' | '\'') =>
+				{
+					start.get_or_insert(idx);
+					let mut escaped = false;
+					for (_, c) in chars.by_ref()
+					{
+						match c
+						{
+							c if c == quote && !escaped => break,
+							'\\' => escaped = !escaped,
+							_ => escaped = false,
+						}
+					}
+				},
+				c if c.is_whitespace() => tokens.extend(start.take().map(|s| &chunk[s..idx])),
+				_ =>
+				{
+					start.get_or_insert(idx);
+				},
+			}
+		}
+		tokens.extend(start.map(|s| &chunk[s..]));
+	}
+	tokens
+}
+
+/// Converts a string literal token (including quotes) into the string it
+/// represents.
+pub(crate) fn unescape_string(literal: &str) -> Result<String, String>
+{
+	let body = literal
+		.strip_prefix('"')
+		.and_then(|s| s.strip_suffix('"'))
+		.ok_or(format!("Unterminated string: {}", literal))?;
+
+	let mut result = String::new();
+	let mut chars = body.chars();
+	while let Some(c) = chars.next()
+	{
+		if c == '\\'
+		{
+			result.push(match chars.next()
+			{
+				Some('n') => '\n',
+				Some('r') => '\r',
+				Some('t') => '\t',
+				Some('0') => '\0',
+				Some(c @ ('\\' | '"' | '\'')) => c,
+				_ => return Err(format!("Invalid escape sequence in string: {}", literal)),
+			});
+		}
+		else
+		{
+			result.push(c);
+		}
+	}
+	Ok(result)
+}
+
+/// Returns the length of the quoted literal at the start of the text,
+/// including both quotes, or the length of the text if it is unterminated.
+pub(crate) fn quoted_len(text: &str) -> usize
+{
+	let quote = text.chars().next();
+	let mut escaped = false;
+	text.char_indices()
+		.skip(1)
+		.find(|(_, c)| {
+			let end = Some(*c) == quote && !escaped;
+			escaped = *c == '\\' && !escaped;
+			end
+		})
+		.map_or(text.len(), |(idx, c)| idx + c.len_utf8())
+}
+
+/// Converts all numeric literals in the token that aren't plain decimal
+/// integers into decimal integers.
+///
+/// This covers hexadecimal (`0x`), binary (`0b`) and octal (`0o`) integers,
+/// `_` separators between digits and character literals (e.g. `'A'` or
+/// `'\n'`).
+/// String literals are left untouched.
+pub(crate) fn normalize_literals(token: &str) -> Result<String, String>
+{
+	let mut result = String::with_capacity(token.len());
+	let mut rest = token;
+	// Whether we are in the middle of a word, where literals can't start
+	let mut in_word = false;
+	while let Some(c) = rest.chars().next()
+	{
+		let len = if c == '"'
+		{
+			quoted_len(rest)
+		}
+		else if c == '\'' && !in_word
+		{
+			let len = quoted_len(rest);
+			let literal = &rest[..len];
+			let mut chars = literal
+				.strip_suffix('\'')
+				.filter(|_| len > 1)
+				.ok_or(format!("Unterminated character literal: {}", literal))
+				.and_then(|body| unescape_string(&format!("\"{}\"", &body[1..])))
+				.map_err(|_| format!("Invalid character literal: {}", literal))?
+				.chars()
+				.collect::<Vec<_>>()
+				.into_iter();
+			match (chars.next(), chars.next())
+			{
+				(Some(c), None) => result.push_str(&(c as u32).to_string()),
+				_ => return Err(format!("Invalid character literal: {}", literal)),
+			}
+			rest = &rest[len..];
+			continue;
+		}
+		else if c.is_ascii_digit() && !in_word
+		{
+			let len = rest
+				.find(|c: char| !(c.is_alphanumeric() || c == '_'))
+				.unwrap_or(rest.len());
+			let literal = &rest[..len];
+			let (radix, digits) = match literal.get(..2)
+			{
+				Some("0x" | "0X") => (16, &literal[2..]),
+				Some("0b" | "0B") => (2, &literal[2..]),
+				Some("0o" | "0O") => (8, &literal[2..]),
+				_ => (10, literal),
+			};
+			// '0b' is a reference to the numeric label '0', not a literal
+			if (radix != 10 && literal != "0b") || literal.contains('_')
+			{
+				let value = u128::from_str_radix(&digits.replace('_', ""), radix)
+					.ok()
+					.filter(|_| !digits.starts_with('_') && !digits.ends_with('_'))
+					.ok_or(format!("Invalid number: {}", literal))?;
+				result.push_str(&value.to_string());
+				rest = &rest[len..];
+				continue;
+			}
+			len
+		}
+		else
+		{
+			c.len_utf8()
+		};
+		in_word = c.is_alphanumeric() || c == '_';
+		result.push_str(&rest[..len]);
+		rest = &rest[len..];
+	}
+	Ok(result)
+}
+
+/// A token of assembly.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) struct Token
+{
+	pub text: String,
+	/// The macro expansion that produced this token, if any.
+	pub expansion: Option<usize>,
+}
+impl Token
+{
+	pub fn as_str(&self) -> &str
+	{
+		self.text.as_str()
+	}
+}
+impl From<&str> for Token
+{
+	fn from(text: &str) -> Self
+	{
+		Self {
+			text: text.to_owned(),
+			expansion: None,
+		}
+	}
+}
+
+/// A position in a list of tokens.
+///
+/// Tokens may be partially consumed, in which case `offset` is the index of
+/// the first unconsumed byte of the token at `idx`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub(crate) struct Pos
+{
+	pub idx: usize,
+	pub offset: usize,
+}
+
+/// Iterator over the remaining tokens of a [`Cursor`]
+pub(crate) type TokenIter<'t> = std::iter::Chain<
+	std::option::IntoIter<&'t str>,
+	Map<Iter<'t, Token>, fn(&'t Token) -> &'t str>,
+>;
+
+/// Reads through a list of tokens.
+///
+/// Can both hand out the remaining tokens to `scry_isa` parsers and parse
+/// tokens itself.
+#[derive(Clone, Debug)]
+pub(crate) struct Cursor<'t>
+{
+	tokens: &'t [Token],
+	pub pos: Pos,
+}
+impl<'t> Cursor<'t>
+{
+	pub fn new(tokens: &'t [Token]) -> Self
+	{
+		Self {
+			tokens,
+			pos: Pos::default(),
+		}
+	}
+
+	/// Whether all tokens have been consumed.
+	pub fn is_done(&self) -> bool
+	{
+		self.pos.idx >= self.tokens.len()
+	}
+
+	/// The unconsumed part of the current token.
+	pub fn current(&self) -> Option<&'t str>
+	{
+		self.tokens
+			.get(self.pos.idx)
+			.map(|t| &t.as_str()[self.pos.offset..])
+	}
+
+	/// The token after the current one.
+	pub fn peek_next(&self) -> Option<&'t str>
+	{
+		self.tokens.get(self.pos.idx + 1).map(Token::as_str)
+	}
+
+	/// Iterates over the unconsumed tokens, starting with the unconsumed part
+	/// of the current token.
+	pub fn iter(&self) -> TokenIter<'t>
+	{
+		self.current().into_iter().chain(
+			self.tokens
+				.get(self.pos.idx + 1..)
+				.unwrap_or(&[])
+				.iter()
+				.map(Token::as_str as fn(&'t Token) -> &'t str),
+		)
+	}
+
+	/// Moves past whatever a parser consumed from [`Cursor::iter`].
+	pub fn advance(&mut self, consumed: CanConsume)
+	{
+		let mut iter = self.iter();
+		let (_, rest) = consumed.advance_iter_in_place(&mut iter);
+		let idx = self.tokens.len() - iter.size_hint().0;
+		self.pos = match rest.filter(|r| !r.is_empty())
+		{
+			Some(rest) =>
+			{
+				Pos {
+					idx: idx - 1,
+					offset: self.tokens[idx - 1].text.len() - rest.len(),
+				}
+			},
+			None => Pos { idx, offset: 0 },
+		};
+	}
+
+	/// The tokens consumed since the given position.
+	///
+	/// Partially consumed tokens only include their consumed part.
+	pub fn consumed_since(&self, start: Pos) -> Vec<&'t str>
+	{
+		let mut consumed = Vec::new();
+		for idx in start.idx..self.tokens.len().min(self.pos.idx + 1)
+		{
+			let mut text = self.tokens[idx].as_str();
+			if idx == self.pos.idx
+			{
+				text = &text[..self.pos.offset];
+			}
+			if idx == start.idx
+			{
+				text = &text[start.offset.min(text.len())..];
+			}
+			if !text.is_empty()
+			{
+				consumed.push(text);
+			}
+		}
+		consumed
+	}
+
+	/// Consumes the given number of bytes from the current token, moving to the
+	/// next token if nothing is left.
+	pub fn bump(&mut self, len: usize)
+	{
+		self.pos.offset += len;
+		if self.current().is_some_and(str::is_empty)
+		{
+			self.pos = Pos {
+				idx: self.pos.idx + 1,
+				offset: 0,
+			};
+		}
+	}
+
+	/// Consumes and returns the rest of the current token.
+	pub fn next_token(&mut self) -> Option<&'t str>
+	{
+		let tok = self.current();
+		if tok.is_some()
+		{
+			self.pos = Pos {
+				idx: self.pos.idx + 1,
+				offset: 0,
+			};
+		}
+		tok
+	}
+
+	/// Consumes the given string if the current token starts with it.
+	pub fn eat(&mut self, s: &str) -> bool
+	{
+		if self.current().is_some_and(|t| t.starts_with(s))
+		{
+			self.bump(s.len());
+			true
+		}
+		else
+		{
+			false
+		}
+	}
+
+	/// Consumes a string literal.
+	pub fn string(&mut self) -> Result<String, String>
+	{
+		let tok = self.current().unwrap_or("");
+		if !tok.starts_with('"')
+		{
+			return Err(format!("Expected string: {}", tok));
+		}
+		let mut escaped = false;
+		let end = tok
+			.char_indices()
+			.skip(1)
+			.find(|(_, c)| {
+				let end = *c == '"' && !escaped;
+				escaped = *c == '\\' && !escaped;
+				end
+			})
+			.map_or(tok.len(), |(idx, _)| idx + 1);
+		let result = unescape_string(&tok[..end]);
+		self.bump(end);
+		result
+	}
+
+	/// Consumes an identifier, made of letters, digits and `_` but not starting
+	/// with a digit.
+	pub fn identifier(&mut self) -> Result<&'t str, String>
+	{
+		let tok = self.current().unwrap_or("");
+		let len = tok
+			.find(|c: char| !(c.is_alphanumeric() || c == '_'))
+			.unwrap_or(tok.len());
+		if len == 0 || tok.starts_with(|c: char| c.is_ascii_digit())
+		{
+			return Err(format!("Expected identifier: {}", tok));
+		}
+		self.bump(len);
+		Ok(&tok[..len])
+	}
+
+	/// Consumes a non-negative decimal integer.
+	pub fn integer(&mut self) -> Result<u64, String>
+	{
+		let tok = self.current().unwrap_or("");
+		let len = tok.find(|c: char| !c.is_ascii_digit()).unwrap_or(tok.len());
+		let value = tok[..len]
+			.parse()
+			.map_err(|_| format!("Expected integer: {}", tok))?;
+		self.bump(len);
+		Ok(value)
+	}
+}
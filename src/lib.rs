@@ -1,7 +1,25 @@
 #![cfg_attr(feature = "fail-on-warnings", deny(warnings))]
 
+mod archive;
 mod assemble;
+mod checksum;
+mod elf;
+mod expr;
+mod labels;
+mod lexer;
+mod link;
+mod loader;
+mod object;
+mod preprocess;
 mod raw;
+mod script;
+mod serialize;
 
+pub use archive::*;
 pub use assemble::*;
+pub use elf::*;
+pub use link::*;
+pub use loader::*;
+pub use object::*;
 pub use raw::*;
+pub use script::*;
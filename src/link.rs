@@ -0,0 +1,716 @@
+use crate::{
+	archive::Archive,
+	object::{Object, Reference, Relocation, RelocationField},
+	script::{Region, Script},
+};
+use byteorder::{ByteOrder, LittleEndian};
+use scry_isa::{Instruction, ParseErrorType, Parser, Resolve};
+use std::{
+	cell::RefCell,
+	collections::{BTreeMap, HashMap, HashSet},
+	fmt::Write,
+};
+
+/// An object to link.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct LinkInput
+{
+	/// Identifies the object in errors.
+	pub name: String,
+	pub object: Object,
+	/// The address the object's first section is placed at.
+	///
+	/// The object's sections are placed one after the other, in order, each
+	/// aligned to [`Section::alignment`](crate::Section::alignment).
+	/// Ignored if the linker is given a [`Script`].
+	pub base: u64,
+}
+
+/// Combines assembled objects into a single image.
+///
+/// Global symbols of each object can be used by all other objects.
+/// Objects should be assembled with
+/// [`Raw::relocatable`](crate::Raw::relocatable) set, such that they can be
+/// placed anywhere.
+#[derive(Clone, Debug, Default)]
+pub struct Linker
+{
+	pub inputs: Vec<LinkInput>,
+	/// Where to place the sections, instead of at the base address of each
+	/// object.
+	pub script: Option<Script>,
+	/// If given, the symbol the program starts at, such that all sections
+	/// that can't be reached from it are discarded.
+	///
+	/// Sections are only reachable through relocations, so all objects must
+	/// be relocatable, such that every reference between sections is one.
+	pub gc_entry: Option<String>,
+	/// Archives, with their names, whose members are added to the inputs if
+	/// they define a symbol the inputs, or other added members, use but don't
+	/// define.
+	///
+	/// Added members are named `archive(member)` and placed after all other
+	/// objects, unless a [`Script`] is given.
+	pub archives: Vec<(String, Archive)>,
+}
+
+/// The result of linking.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Image
+{
+	/// The contents of all sections at their final addresses, in order of
+	/// address.
+	///
+	/// Sections without contents (see
+	/// [`SectionFlags::no_bits`](crate::SectionFlags::no_bits)) are left out,
+	/// so memory regions far apart don't need the space between them.
+	pub segments: Vec<Segment>,
+	/// The address of each global symbol, including those defined by the
+	/// linker script.
+	pub symbols: BTreeMap<String, u64>,
+	/// A human-readable description of where everything was placed.
+	pub map: String,
+	/// The sections discarded because they weren't reachable from
+	/// [`Linker::gc_entry`].
+	pub discarded: Vec<Discarded>,
+}
+
+/// Sections placed directly after each other in an [`Image`].
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Segment
+{
+	/// The address of the first byte.
+	pub address: u64,
+	pub bytes: Vec<u8>,
+}
+
+/// A section discarded by the linker.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Discarded
+{
+	/// The name of the object the section was in.
+	pub object: String,
+	pub section: String,
+	/// The number of bytes saved by discarding the section.
+	pub size: usize,
+}
+
+impl Linker
+{
+	/// Places each object at its base address, or as described by the script,
+	/// resolves the references between them and patches all relocations.
+	pub fn link(&self) -> Result<Image, String>
+	{
+		let mut inputs = self.inputs.clone();
+		add_archive_members(&mut inputs, &self.archives)?;
+		let (inputs, discarded) = match &self.gc_entry
+		{
+			Some(entry) => collect_garbage(&inputs, entry)?,
+			None => (inputs, Vec::new()),
+		};
+		let inputs = &inputs;
+
+		let mut image = if let Some(script) = &self.script
+		{
+			let layout = script.place(inputs)?;
+			link_placed(inputs, &layout.addresses, &layout.symbols, &layout.usage)?
+		}
+		else
+		{
+			link_placed(inputs, &base_addresses(inputs)?, &[], &[])?
+		};
+		if !discarded.is_empty()
+		{
+			let saved: usize = discarded.iter().map(|d| d.size).sum();
+			writeln!(image.map, "Discarded sections ({} bytes saved):", saved).unwrap();
+			for section in &discarded
+			{
+				writeln!(
+					image.map,
+					"  {:#010x} {:<16} {}",
+					section.size, section.section, section.object
+				)
+				.unwrap();
+			}
+		}
+		image.discarded = discarded;
+		Ok(image)
+	}
+}
+
+/// Adds the members of the archives that define symbols used, but not defined,
+/// by the inputs, until no more members are needed.
+///
+/// Each member added must define the symbol it was added for, so every round
+/// defines a new symbol and no member is added twice.
+fn add_archive_members(
+	inputs: &mut Vec<LinkInput>,
+	archives: &[(String, Archive)],
+) -> Result<(), String>
+{
+	loop
+	{
+		let defined: HashSet<&str> = inputs
+			.iter()
+			.flat_map(|input| input.object.exports())
+			.map(|label| label.name.as_str())
+			.collect();
+		let needed = inputs
+			.iter()
+			.flat_map(|input| &input.object.imports)
+			.filter(|sym| !defined.contains(sym.as_str()))
+			.find_map(|sym| {
+				archives.iter().find_map(|(name, archive)| {
+					archive
+						.index
+						.get(sym)
+						.map(|member| (sym, name, archive.members.get(*member)))
+				})
+			});
+		let Some((sym, archive, member)) = needed
+		else
+		{
+			return Ok(());
+		};
+		let Some((member, object)) =
+			member.filter(|(_, object)| object.exports().any(|label| label.name == *sym))
+		else
+		{
+			return Err(format!(
+				"Archive '{}' indexes '{}' to a member not defining it",
+				archive, sym
+			));
+		};
+		let mut base = 0;
+		for input in inputs.iter()
+		{
+			base = base.max(place_sections(input)?.1);
+		}
+		inputs.push(LinkInput {
+			name: format!("{}({})", archive, member),
+			object: object.clone(),
+			base,
+		});
+	}
+}
+
+/// Places the sections of each object one after the other, starting at the
+/// object's base address.
+fn base_addresses(inputs: &[LinkInput]) -> Result<Vec<Vec<u64>>, String>
+{
+	inputs
+		.iter()
+		.map(|input| place_sections(input).map(|(addresses, _)| addresses))
+		.collect()
+}
+
+/// Places the sections of the object one after the other, starting at its base
+/// address, each aligned to [`Section::alignment`](crate::Section::alignment).
+///
+/// Returns the address of each section and the end of the last one.
+fn place_sections(input: &LinkInput) -> Result<(Vec<u64>, u64), String>
+{
+	let mut addresses = Vec::new();
+	let mut end = input.base;
+	for section in &input.object.sections
+	{
+		let start = end
+			.checked_next_multiple_of(section.alignment() as u64)
+			.ok_or(format!("Address out of range in '{}'", input.name))?;
+		end = start
+			.checked_add(section.size as u64)
+			.ok_or(format!("Address out of range in '{}'", input.name))?;
+		addresses.push(start);
+	}
+	Ok((addresses, end))
+}
+
+/// Removes the contents and labels of all sections that can't be reached from
+/// the given symbol, returning the remaining objects and what was removed.
+///
+/// A section reaches another if it has a relocation referring to it, so
+/// objects that aren't relocatable can't be collected.
+fn collect_garbage(
+	inputs: &[LinkInput],
+	entry: &str,
+) -> Result<(Vec<LinkInput>, Vec<Discarded>), String>
+{
+	if let Some(input) = inputs.iter().find(|input| !input.object.relocatable)
+	{
+		return Err(format!(
+			"Can't discard sections of '{}', as it isn't relocatable",
+			input.name
+		));
+	}
+	// The object and section defining each global symbol
+	let mut globals = HashMap::new();
+	for (idx, input) in inputs.iter().enumerate()
+	{
+		for label in input.object.exports()
+		{
+			globals
+				.entry(label.name.as_str())
+				.or_insert((idx, label.section));
+		}
+	}
+	let root = *globals
+		.get(entry)
+		.ok_or(format!("Undefined entry symbol: {}", entry))?;
+	let mut reachable = HashSet::from([root]);
+	let mut unvisited = vec![root];
+	while let Some((idx, section)) = unvisited.pop()
+	{
+		let relocations = inputs[idx].object.relocations.iter();
+		for relocation in relocations.filter(|r| r.section == section)
+		{
+			let symbols = relocation.reference.symbols();
+			for (sym, target) in symbols.into_iter().zip(&relocation.targets)
+			{
+				let referenced = match target
+				{
+					Some((section, _)) => Some((idx, *section)),
+					None => globals.get(sym).copied(),
+				};
+				if let Some(referenced) = referenced.filter(|r| reachable.insert(*r))
+				{
+					unvisited.push(referenced);
+				}
+			}
+		}
+	}
+
+	let mut collected = inputs.to_vec();
+	let mut discarded = Vec::new();
+	for (idx, input) in collected.iter_mut().enumerate()
+	{
+		let object = &mut input.object;
+		let mut removed = HashSet::new();
+		for (section_idx, section) in object.sections.iter_mut().enumerate()
+		{
+			if section.size > 0 && !reachable.contains(&(idx, section_idx))
+			{
+				discarded.push(Discarded {
+					object: input.name.clone(),
+					section: section.name.clone(),
+					size: section.size,
+				});
+				section.bytes.clear();
+				section.size = 0;
+				removed.insert(section_idx);
+			}
+		}
+		object
+			.labels
+			.retain(|label| !removed.contains(&label.section));
+		object.relocations.retain(|r| !removed.contains(&r.section));
+	}
+	Ok((collected, discarded))
+}
+
+/// Links the given objects, where `addresses` gives the address of each
+/// section of each object.
+///
+/// `symbols` are defined in addition to the objects' global symbols, while
+/// `usage` gives how much of each memory region is used, for the map.
+fn link_placed(
+	inputs: &[LinkInput],
+	addresses: &[Vec<u64>],
+	symbols: &[(String, u64)],
+	usage: &[(Region, u64)],
+) -> Result<Image, String>
+{
+	// Addresses are kept as 'i128', such that distances between them fit
+	let address = |input: usize, section: usize, offset: usize| {
+		addresses[input][section]
+			.checked_add(offset as u64)
+			.map(|addr| addr as i128)
+			.ok_or(format!("Address out of range in '{}'", inputs[input].name))
+	};
+
+	// Resolve the global symbols, where those from the script have no object
+	let mut globals: BTreeMap<&str, (i128, Option<usize>)> = BTreeMap::new();
+	for (name, addr) in symbols
+	{
+		if globals.insert(name, (*addr as i128, None)).is_some()
+		{
+			return Err(format!("'{}' defined twice in the linker script", name));
+		}
+	}
+	for (idx, input) in inputs.iter().enumerate()
+	{
+		for label in input.object.exports()
+		{
+			let addr = address(idx, label.section, label.offset)?;
+			if let Some((_, first)) = globals.insert(&label.name, (addr, Some(idx)))
+			{
+				return Err(format!(
+					"'{}' defined in both {} and '{}'",
+					label.name,
+					first.map_or("the linker script".to_owned(), |first| {
+						format!("'{}'", inputs[first].name)
+					}),
+					input.name
+				));
+			}
+		}
+	}
+
+	// Sections must not overlap
+	let mut placed = Vec::new();
+	for (idx, input) in inputs.iter().enumerate()
+	{
+		for (section_idx, section) in input.object.sections.iter().enumerate()
+		{
+			if section.size > 0
+			{
+				let start = addresses[idx][section_idx];
+				let end = start
+					.checked_add(section.size as u64)
+					.ok_or(format!("Address out of range in '{}'", input.name))?;
+				placed.push((start, end, idx, section_idx));
+			}
+		}
+	}
+	placed.sort();
+	for pair in placed.windows(2)
+	{
+		let ((_, end, input1, section1), (start, _, input2, section2)) = (pair[0], pair[1]);
+		if start < end
+		{
+			return Err(format!(
+				"Section '{}' of '{}' overlaps section '{}' of '{}'",
+				inputs[input1].object.sections[section1].name,
+				inputs[input1].name,
+				inputs[input2].object.sections[section2].name,
+				inputs[input2].name
+			));
+		}
+	}
+
+	let mut sections: Vec<Vec<Vec<u8>>> = inputs
+		.iter()
+		.map(|input| {
+			input
+				.object
+				.sections
+				.iter()
+				.map(|s| s.bytes.clone())
+				.collect()
+		})
+		.collect();
+	for (idx, input) in inputs.iter().enumerate()
+	{
+		// The final value of the given relocation's reference
+		let value = |relocation: &Relocation| {
+			let symbols = relocation.reference.symbols();
+			let mut values = Vec::new();
+			for (sym, target) in symbols.into_iter().zip(&relocation.targets)
+			{
+				values.push(match target
+				{
+					Some((section, offset)) => address(idx, *section, *offset)?,
+					None =>
+					{
+						globals.get(sym).map(|(addr, _)| *addr).ok_or(format!(
+							"Undefined symbol '{}' referenced in '{}'",
+							sym, input.name
+						))?
+					},
+				});
+			}
+			Ok::<_, String>(match relocation.reference
+			{
+				Reference::Address(_) => values[0],
+				Reference::DistanceCurrent(_) =>
+				{
+					values[0] - address(idx, relocation.section, relocation.offset)?
+				},
+				Reference::Distance(..) => values[1] - values[0],
+			})
+		};
+
+		for relocation in &input.object.relocations
+		{
+			let bytes = &mut sections[idx][relocation.section];
+			match relocation.field
+			{
+				RelocationField::Bytes { size, big_endian } =>
+				{
+					let value = value(relocation)?;
+					let bits = size as u32 * 8;
+					if bits < 128 && (value < -(1 << (bits - 1)) || value >= 1 << bits)
+					{
+						return Err(format!(
+							"Value out of range for {}-byte field in '{}': {}",
+							size, input.name, value
+						));
+					}
+					let mut value = value.to_le_bytes()[..size].to_vec();
+					if big_endian
+					{
+						value.reverse();
+					}
+					bytes[relocation.offset..relocation.offset + size].copy_from_slice(&value);
+				},
+				_ =>
+				{
+					// Instructions with multiple relocations are encoded once
+					let location = (relocation.section, relocation.offset);
+					let first = input
+						.object
+						.relocations
+						.iter()
+						.find(|other| (other.section, other.offset) == location);
+					if first.is_some_and(|first| std::ptr::eq(first, relocation))
+					{
+						let instr = reencode(input, relocation, &value)?;
+						LittleEndian::write_u16(
+							&mut bytes[relocation.offset..relocation.offset + 2],
+							instr.encode(),
+						);
+					}
+				},
+			}
+		}
+	}
+
+	let mut map = String::new();
+	if !usage.is_empty()
+	{
+		map.push_str("Memory regions:\n");
+		for (region, used) in usage
+		{
+			writeln!(
+				map,
+				"  {:<16} {:#010x} {:#010x} used {:#x}",
+				region.name, region.origin, region.length, used
+			)
+			.unwrap();
+		}
+	}
+	map.push_str("Sections:\n");
+	for (start, end, input, section) in &placed
+	{
+		writeln!(
+			map,
+			"  {:#010x} {:#010x} {:<16} {}",
+			start,
+			end - start,
+			inputs[*input].object.sections[*section].name,
+			inputs[*input].name
+		)
+		.unwrap();
+	}
+	map.push_str("Symbols:\n");
+	for (name, (addr, _)) in &globals
+	{
+		writeln!(map, "  {:#010x} {}", addr, name).unwrap();
+	}
+
+	let mut segments: Vec<Segment> = Vec::new();
+	for (start, end, input, section) in placed
+	{
+		if inputs[input].object.sections[section].flags.no_bits
+		{
+			continue;
+		}
+		let mut contents = std::mem::take(&mut sections[input][section]);
+		contents.resize((end - start) as usize, 0);
+		match segments.last_mut()
+		{
+			Some(last) if last.address + last.bytes.len() as u64 == start =>
+			{
+				last.bytes.extend(contents)
+			},
+			_ =>
+			{
+				segments.push(Segment {
+					address: start,
+					bytes: contents,
+				})
+			},
+		}
+	}
+	Ok(Image {
+		segments,
+		symbols: globals
+			.into_iter()
+			.map(|(name, (addr, _))| (name.to_owned(), addr as u64))
+			.collect(),
+		map,
+		discarded: Vec::new(),
+	})
+}
+
+/// Prefix of the symbols that replace the names of symbols when parsing
+/// instructions again.
+const PARSED_SYMBOL: &str = "__symbol_";
+
+/// Replaces the given symbols in an operand of an instruction's source with
+/// symbols `scry_isa` accepts, as names like `func.loop`, `1f` or
+/// `{table + 2}` can't be parsed.
+fn replace_symbols(operand: &str, symbols: &[&str]) -> String
+{
+	let mut result = String::new();
+	let mut rest = operand;
+	while let Some(c) = rest.chars().next()
+	{
+		let len = if c == '{'
+		{
+			let mut depth = 0;
+			rest.find(|c| {
+				match c
+				{
+					'{' => depth += 1,
+					'}' => depth -= 1,
+					_ => (),
+				}
+				depth == 0
+			})
+			.map_or(rest.len(), |end| end + 1)
+		}
+		else if c.is_alphanumeric() || c == '_' || c == '.'
+		{
+			rest.find(|c: char| !(c.is_alphanumeric() || c == '_' || c == '.'))
+				.unwrap_or(rest.len())
+		}
+		else
+		{
+			c.len_utf8()
+		};
+		match symbols.iter().position(|sym| *sym == &rest[..len])
+		{
+			Some(idx) => result.push_str(&format!("{}{}", PARSED_SYMBOL, idx)),
+			None => result.push_str(&rest[..len]),
+		}
+		rest = &rest[len..];
+	}
+	result
+}
+
+/// Parses the instruction containing the given relocation again, giving each
+/// relocation at its location the value from `value`.
+fn reencode<F>(input: &LinkInput, relocation: &Relocation, value: &F) -> Result<Instruction, String>
+where
+	F: Fn(&Relocation) -> Result<i128, String>,
+{
+	// The symbols referenced by the instruction
+	let mut symbols: Vec<&str> = Vec::new();
+	let located =
+		input.object.relocations.iter().filter(|other| {
+			(other.section, other.offset) == (relocation.section, relocation.offset)
+		});
+	for reference in relocation
+		.resolved
+		.iter()
+		.map(|(reference, _)| reference)
+		.chain(located.map(|other| &other.reference))
+	{
+		for sym in reference.symbols()
+		{
+			if !symbols.contains(&sym)
+			{
+				symbols.push(sym);
+			}
+		}
+	}
+	// The mnemonic is kept, as it may look like a symbol
+	let source: Vec<_> = relocation
+		.source
+		.iter()
+		.enumerate()
+		.map(|(idx, token)| {
+			if idx == 0
+			{
+				token.clone()
+			}
+			else
+			{
+				replace_symbols(token, &symbols)
+			}
+		})
+		.collect();
+	parse_relocated(input, relocation, &source, &symbols, value)
+}
+
+/// Parses the given source of the instruction containing the relocation, in
+/// which the given symbols have been replaced.
+fn parse_relocated<'a, F>(
+	input: &LinkInput,
+	relocation: &Relocation,
+	source: &'a [String],
+	symbols: &[&str],
+	value: &F,
+) -> Result<Instruction, String>
+where
+	F: Fn(&Relocation) -> Result<i128, String>,
+{
+	let error = RefCell::new(None);
+	let resolve = |resolve: Resolve<'a>| {
+		let reference = Reference::from(&resolve).map_symbols(|sym| {
+			sym.strip_prefix(PARSED_SYMBOL)
+				.and_then(|idx| symbols.get(idx.parse::<usize>().ok()?))
+				.unwrap_or(&sym)
+				.to_string()
+		});
+		let sym = match resolve
+		{
+			Resolve::Address(sym) | Resolve::DistanceCurrent(sym) => sym,
+			Resolve::Distance(_, sym) => sym,
+		};
+		let resolved = relocation
+			.resolved
+			.iter()
+			.find(|(resolved, _)| *resolved == reference)
+			.map(|(_, value)| Ok(*value as i128))
+			.or_else(|| {
+				input
+					.object
+					.relocations
+					.iter()
+					.filter(|other| {
+						(other.section, other.offset) == (relocation.section, relocation.offset)
+					})
+					.find(|other| other.reference == reference)
+					.map(value)
+			})
+			.unwrap_or_else(|| {
+				Err(format!(
+					"Unknown reference in '{}': {:?}",
+					input.name, reference
+				))
+			})
+			.and_then(|value| {
+				i32::try_from(value).map_err(|_| format!("Value out of range: {}", value))
+			});
+		resolved.map_err(|err| {
+			error.replace(Some(err));
+			sym
+		})
+	};
+	match Instruction::parse(source.iter().map(String::as_str), resolve)
+	{
+		Ok((instr, _)) => Ok(instr),
+		Err(err) =>
+		{
+			Err(error.take().unwrap_or(match err.err_type
+			{
+				ParseErrorType::OutOfBoundValue(val, min, max) =>
+				{
+					format!(
+						"Relocation out of range in '{}' (value, minimum, maximum): {}, {}, {}",
+						input.name, val, min, max
+					)
+				},
+				_ =>
+				{
+					format!(
+						"Failed to encode instruction in '{}': {}",
+						input.name,
+						relocation.source.concat()
+					)
+				},
+			}))
+		},
+	}
+}
@@ -0,0 +1,39 @@
+use std::{collections::HashMap, fs, path::PathBuf};
+
+/// Provides the contents of files referenced by assembly, e.g. using
+/// `.incbin`.
+///
+/// All file access of the assembler goes through a loader, such that users can
+/// choose where files are looked up, or provide them from memory.
+pub trait Loader
+{
+	/// Returns the full contents of the file with the given path.
+	fn load(&self, path: &str) -> Result<Vec<u8>, String>;
+}
+
+/// Loads files from the file system.
+///
+/// Relative paths are resolved from `root`, which defaults to the current
+/// working directory.
+#[derive(Clone, Debug, Default)]
+pub struct FileLoader
+{
+	pub root: PathBuf,
+}
+
+impl Loader for FileLoader
+{
+	fn load(&self, path: &str) -> Result<Vec<u8>, String>
+	{
+		fs::read(self.root.join(path)).map_err(|err| err.to_string())
+	}
+}
+
+/// Serves files from memory, keyed by their path.
+impl Loader for HashMap<String, Vec<u8>>
+{
+	fn load(&self, path: &str) -> Result<Vec<u8>, String>
+	{
+		self.get(path).cloned().ok_or("File not found".to_owned())
+	}
+}
@@ -0,0 +1,305 @@
+use scry_isa::Resolve;
+
+/// The result of assembling source files.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Object
+{
+	/// The sections, in the order they first appear in the source.
+	pub sections: Vec<Section>,
+	/// All labels declared in the source.
+	pub labels: Vec<Label>,
+	/// Symbols declared with `.extern` but not defined in the source, in
+	/// order of declaration.
+	///
+	/// If assembled with [`Raw::relocatable`](crate::Raw::relocatable) set,
+	/// also contains all other undefined symbols, in order of first use.
+	pub imports: Vec<String>,
+	/// The fields that must be patched once the addresses of imported symbols,
+	/// or of sections if relocatable, are known.
+	pub relocations: Vec<Relocation>,
+	/// Whether assembled with [`Raw::relocatable`](crate::Raw::relocatable)
+	/// set, such that all references between sections are relocations.
+	pub relocatable: bool,
+	/// Warnings reported during assembly, e.g. by `.warning`.
+	pub warnings: Vec<String>,
+}
+
+impl Object
+{
+	/// Returns the section with the given name, if present.
+	pub fn section(&self, name: &str) -> Option<&Section>
+	{
+		self.sections.iter().find(|section| section.name == name)
+	}
+
+	/// Returns the label with the given name, if present.
+	pub fn label(&self, name: &str) -> Option<&Label>
+	{
+		self.labels.iter().find(|label| label.name == name)
+	}
+
+	/// Returns the labels visible to other objects.
+	pub fn exports(&self) -> impl Iterator<Item = &Label>
+	{
+		self.labels
+			.iter()
+			.filter(|label| label.visibility == Visibility::Global)
+	}
+
+	/// Lays out all sections one after the other, in order, each aligned to
+	/// [`Section::alignment`].
+	///
+	/// Sections without contents and padding are filled with zeros.
+	pub fn bytes(&self) -> Vec<u8>
+	{
+		let mut bytes = Vec::with_capacity(self.sections.iter().map(|s| s.size).sum());
+		for (section, offset) in self.sections.iter().zip(section_offsets(&self.sections))
+		{
+			bytes.resize(offset, 0);
+			bytes.extend_from_slice(&section.bytes);
+			bytes.resize(offset + section.size, 0);
+		}
+		bytes
+	}
+}
+
+/// Returns the offset of each section when laid out one after the other, in
+/// order, as done by [`Object::bytes`].
+pub(crate) fn section_offsets(sections: &[Section]) -> Vec<usize>
+{
+	sections
+		.iter()
+		.scan(0, |end: &mut usize, section| {
+			let start = end.next_multiple_of(section.alignment());
+			*end = start + section.size;
+			Some(start)
+		})
+		.collect()
+}
+
+/// A named block of assembled bytes with its own location counter.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Section
+{
+	pub name: String,
+	pub flags: SectionFlags,
+	/// The contents of the section.
+	///
+	/// Empty if the section has no contents (see [`SectionFlags::no_bits`]).
+	pub bytes: Vec<u8>,
+	/// The size of the section in bytes.
+	pub size: usize,
+}
+
+impl Section
+{
+	pub fn new(name: &str, flags: SectionFlags) -> Self
+	{
+		Self {
+			name: name.to_owned(),
+			flags,
+			..Self::default()
+		}
+	}
+
+	/// The number of bytes the start of the section is aligned to.
+	///
+	/// Executable sections are aligned to instructions, while others need no
+	/// alignment.
+	pub fn alignment(&self) -> usize
+	{
+		if self.flags.execute
+		{
+			2
+		}
+		else
+		{
+			1
+		}
+	}
+}
+
+/// Properties of a section.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct SectionFlags
+{
+	/// Whether the section may be written to at runtime.
+	pub write: bool,
+	/// Whether the section contains instructions.
+	pub execute: bool,
+	/// Whether the section only reserves space, which is zeroed at runtime,
+	/// instead of having contents.
+	pub no_bits: bool,
+}
+
+impl SectionFlags
+{
+	/// The flags a section gets if none are given explicitly.
+	///
+	/// `.text`, `.data` and `.bss` (and their subsections, like `.text.start`)
+	/// get their usual flags, while other sections are read-only data.
+	pub fn default_for(name: &str) -> Self
+	{
+		let is = |prefix: &str| name == prefix || name.starts_with(&format!("{}.", prefix));
+		Self {
+			write: is(".data") || is(".bss"),
+			execute: is(".text"),
+			no_bits: is(".bss"),
+		}
+	}
+}
+
+/// A label declared in the source.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Label
+{
+	pub name: String,
+	/// The index of the section the label is in.
+	pub section: usize,
+	/// The offset of the label from the start of its section.
+	pub offset: usize,
+	pub visibility: Visibility,
+	/// What the label refers to, as declared using `.type` or `.func`.
+	pub kind: SymbolKind,
+	/// The size in bytes of what the label refers to, as declared using
+	/// `.size` or `.func`/`.endfunc`.
+	pub size: Option<usize>,
+	/// The name of the file the label is declared in, if assembled using
+	/// [`Raw::assemble_files`](crate::Raw::assemble_files).
+	pub file: Option<String>,
+}
+
+/// A field whose value depends on symbols that couldn't be resolved during
+/// assembly, or on where sections are placed.
+///
+/// Until patched, the field is assembled as if imported symbols were directly
+/// after the instruction or data containing the field.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Relocation
+{
+	/// The index of the section containing the field.
+	pub section: usize,
+	/// The offset of the instruction or data containing the field from the
+	/// start of its section.
+	pub offset: usize,
+	pub field: RelocationField,
+	/// The value the field is given.
+	///
+	/// The symbols are named as in [`Relocation::source`]: labels by their
+	/// full names, numeric labels by references like `1f`, and expressions by
+	/// their text in braces.
+	pub reference: Reference,
+	/// For each symbol of the reference, the section and offset of the label
+	/// it refers to, if declared in the object.
+	pub targets: Vec<Option<(usize, usize)>>,
+	/// The tokens of the instruction containing the field, such that it can be
+	/// parsed again with the final value of the field.
+	///
+	/// Empty for data.
+	pub source: Vec<String>,
+	/// The values of the instruction's other references, as resolved during
+	/// assembly.
+	///
+	/// The symbols are named as in [`Relocation::source`].
+	pub resolved: Vec<(Reference, i64)>,
+}
+
+/// What kind of field a relocation patches.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RelocationField
+{
+	/// The output offset of an instruction, e.g. `=>label`.
+	OutputOffset,
+	/// The target of a `jmp` or `call`.
+	JumpTarget,
+	/// The location of a `jmp` or `ret`, where control is transferred.
+	JumpLocation,
+	/// The immediate of a `const`.
+	ConstImmediate,
+	/// A value produced by `.bytes`.
+	Bytes
+	{
+		/// The width of the value in bytes.
+		size: usize,
+		/// Whether the value is stored most significant byte first.
+		big_endian: bool,
+	},
+}
+
+/// The value of a field in terms of symbols.
+///
+/// Mirrors [`scry_isa::Resolve`], but owns its symbols.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub enum Reference
+{
+	/// The address of the symbol.
+	Address(String),
+	/// The distance from the instruction containing the field to the symbol.
+	DistanceCurrent(String),
+	/// The distance from the first symbol to the second.
+	Distance(String, String),
+}
+
+impl Reference
+{
+	/// The symbols the value depends on.
+	pub fn symbols(&self) -> Vec<&str>
+	{
+		match self
+		{
+			Reference::Address(sym) | Reference::DistanceCurrent(sym) => vec![sym],
+			Reference::Distance(sym1, sym2) => vec![sym1, sym2],
+		}
+	}
+
+	/// Returns the same reference, with each symbol replaced by `f`'s result.
+	pub fn map_symbols(&self, f: impl Fn(&str) -> String) -> Self
+	{
+		match self
+		{
+			Reference::Address(sym) => Reference::Address(f(sym)),
+			Reference::DistanceCurrent(sym) => Reference::DistanceCurrent(f(sym)),
+			Reference::Distance(sym1, sym2) => Reference::Distance(f(sym1), f(sym2)),
+		}
+	}
+}
+
+impl From<&Resolve<'_>> for Reference
+{
+	fn from(resolve: &Resolve) -> Self
+	{
+		match resolve
+		{
+			Resolve::Address(sym) => Reference::Address(sym.to_string()),
+			Resolve::DistanceCurrent(sym) => Reference::DistanceCurrent(sym.to_string()),
+			Resolve::Distance(sym1, sym2) =>
+			{
+				Reference::Distance(sym1.to_string(), sym2.to_string())
+			},
+		}
+	}
+}
+
+/// Whether a label can be referred to from other objects.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Visibility
+{
+	/// Only visible in its own source (the default).
+	#[default]
+	Local,
+	/// Exported using `.global`.
+	Global,
+}
+
+/// What a label refers to.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum SymbolKind
+{
+	/// Not declared.
+	#[default]
+	Unknown,
+	/// The start of a function.
+	Function,
+	/// A data object, e.g. a variable or table.
+	Object,
+}
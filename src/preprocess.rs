@@ -0,0 +1,668 @@
+use crate::{
+	expr::Expr,
+	lexer::{tokenize, Cursor, Pos, Token},
+};
+use std::collections::{HashMap, HashSet};
+
+/// How deeply macro expansions may nest before we assume infinite recursion.
+const MAX_EXPANSION_DEPTH: usize = 256;
+
+/// How many tokens repetitions may expand to in total, such that huge counts
+/// fail instead of running out of memory.
+const MAX_REPETITION_TOKENS: usize = 1 << 20;
+
+/// A macro defined using `.macro`.
+struct Macro
+{
+	/// The name and default value of each parameter.
+	params: Vec<(String, Option<String>)>,
+	/// The tokens between the parameters and `.endm`, before substitution.
+	body: Vec<String>,
+}
+
+/// An expansion of a macro.
+struct Expansion
+{
+	name: String,
+	/// The expansion the macro was invoked from, if any.
+	parent: Option<usize>,
+}
+
+/// All macro expansions performed while preprocessing.
+///
+/// Tokens refer to the expansion that produced them by index.
+#[derive(Default)]
+pub(crate) struct Expansions(Vec<Expansion>);
+impl Expansions
+{
+	/// Appends the chain of macro expansions that produced the given token to
+	/// an error message.
+	pub fn backtrace(&self, mut err: String, token: Option<&Token>) -> String
+	{
+		let mut expansion = token.and_then(|t| t.expansion);
+		while let Some(idx) = expansion
+		{
+			err.push_str("\nIn expansion of macro '");
+			err.push_str(&self.0[idx].name);
+			err.push('\'');
+			expansion = self.0[idx].parent;
+		}
+		err
+	}
+
+	/// Records a new expansion of the given macro, invoked from the given
+	/// expansion.
+	///
+	/// Returns the index of the new expansion.
+	fn push(&mut self, name: &str, parent: Option<usize>) -> Result<usize, String>
+	{
+		let mut depth = 0;
+		let mut expansion = parent;
+		while let Some(idx) = expansion
+		{
+			depth += 1;
+			expansion = self.0[idx].parent;
+		}
+		if depth >= MAX_EXPANSION_DEPTH
+		{
+			return Err(format!(
+				"Macro expansion too deep (maximum {}), is '{}' recursive?",
+				MAX_EXPANSION_DEPTH, name
+			));
+		}
+		self.0.push(Expansion {
+			name: name.to_owned(),
+			parent,
+		});
+		Ok(self.0.len() - 1)
+	}
+}
+
+/// Expands all macros in the given tokens, removing their definitions.
+///
+/// Macros are defined with `.macro name(param1, param2=default)`, followed by
+/// the body and then `.endm`.
+/// The parameter list is optional for macros without parameters.
+/// Macros are invoked with `name(arg1, arg2)`, where arguments can also be
+/// given by name, e.g. `name(param2=value)`.
+/// Arguments may be left out if the parameter has a default value.
+/// A macro's name is expanded wherever it appears, even as an operand, so
+/// labels can't have the same name as a macro.
+///
+/// In the body, `\param` is replaced by the value of the parameter, while `\@`
+/// is replaced by a number that is unique to each expansion, which can be used
+/// to make labels unique, e.g. `loop\@:`.
+///
+/// Repetitions are also expanded:
+/// `.rept count[, index]` repeats its body `count` times, with `\index`
+/// replaced by the iteration number (starting from 0).
+/// `.irp sym, value1, value2` repeats its body for each value, with `\sym`
+/// replaced by the value.
+/// Both are terminated by `.endr`.
+/// Together, repetitions may expand to at most 2^20 tokens.
+///
+/// Finally, conditional blocks are resolved, such that only their assembled
+/// part remains:
+/// `.if expr` assembles its part if the constant expression is not zero,
+/// `.ifdef sym` if the symbol is defined, and `.ifndef sym` if it isn't.
+/// Symbols are defined either by the given constants or as labels before the
+/// conditional block.
+/// The optional `.else` part is assembled if the first part isn't, and the
+/// block is terminated by `.endif`.
+pub(crate) fn preprocess(
+	mut tokens: Vec<Token>,
+	defines: &HashMap<String, i128>,
+	expansions: &mut Expansions,
+) -> Result<Vec<Token>, String>
+{
+	let mut macros: HashMap<String, Macro> = HashMap::new();
+	let mut labels = HashSet::new();
+	// For each conditional block we are in, whether we are in its '.else' part
+	let mut conditionals = Vec::new();
+	let mut result: Vec<Token> = Vec::with_capacity(tokens.len());
+	// The number of tokens produced by repetitions so far
+	let mut repeated = 0usize;
+	let mut idx = 0;
+	while let Some(token) = tokens.get(idx)
+	{
+		let mut cursor = Cursor::new(&tokens[idx..]);
+		let in_context = |err| expansions.backtrace(err, Some(token));
+		match token.as_str()
+		{
+			".macro" =>
+			{
+				cursor.next_token();
+				let (name, mac) = parse_macro_definition(&mut cursor).map_err(in_context)?;
+				if macros.contains_key(&name)
+				{
+					return Err(in_context(format!("Macro '{}' defined twice", name)));
+				}
+				if labels.contains(&name)
+				{
+					return Err(in_context(format!("Macro '{}' named like a label", name)));
+				}
+				macros.insert(name, mac);
+				idx += cursor.pos.idx;
+			},
+			".endm" => return Err(in_context("'.endm' without '.macro'".to_owned())),
+			".rept" | ".irp" =>
+			{
+				cursor.next_token();
+				let iterations = if token.as_str() == ".rept"
+				{
+					parse_rept(&mut cursor, defines)
+				}
+				else
+				{
+					parse_irp(&mut cursor)
+				}
+				.and_then(|iterations| {
+					parse_body(&mut cursor, &[".rept", ".irp"], ".endr")
+						.map(|body| (iterations, body))
+				});
+				let (iterations, body) = iterations.map_err(in_context)?;
+				repeated = iterations
+					.len()
+					.checked_mul(body.len())
+					.and_then(|size| repeated.checked_add(size))
+					.filter(|total| *total <= MAX_REPETITION_TOKENS)
+					.ok_or(in_context(format!(
+						"Repetitions too large (maximum {} tokens)",
+						MAX_REPETITION_TOKENS
+					)))?;
+
+				let expanded = iterations
+					.iter()
+					.flat_map(|bindings| expand_body(&body, bindings, None, token.expansion))
+					.collect();
+				let pos = cursor.pos;
+				replace_consumed(&mut tokens, idx, pos, expanded);
+			},
+			".endr" => return Err(in_context("'.endr' without '.rept' or '.irp'".to_owned())),
+			".if" | ".ifdef" | ".ifndef" =>
+			{
+				cursor.next_token();
+				let condition = if token.as_str() == ".if"
+				{
+					Expr::parse(&mut cursor)
+						.and_then(|expr| {
+							expr.evaluate(&|sym| {
+								defines
+									.get(sym)
+									.cloned()
+									.ok_or(format!("Unknown constant: {}", sym))
+							})
+						})
+						.map(|value| value != 0)
+				}
+				else
+				{
+					cursor.identifier().map(|sym| {
+						let defined = defines.contains_key(sym) || labels.contains(sym);
+						defined == (token.as_str() == ".ifdef")
+					})
+				}
+				.map_err(in_context)?;
+
+				let mut pos = cursor.pos;
+				if !condition
+				{
+					// Skip to the '.else' or '.endif'
+					let (end, is_else) = skip_conditional(&tokens[idx..], pos, true);
+					pos = end.ok_or(in_context("'.if' without '.endif'".to_owned()))?;
+					if !is_else
+					{
+						replace_consumed(&mut tokens, idx, pos, Vec::new());
+						continue;
+					}
+				}
+				conditionals.push(!condition);
+				replace_consumed(&mut tokens, idx, pos, Vec::new());
+			},
+			".else" =>
+			{
+				match conditionals.pop()
+				{
+					None => return Err(in_context("'.else' without '.if'".to_owned())),
+					Some(true) => return Err(in_context("'.else' after '.else'".to_owned())),
+					Some(false) =>
+					{
+						// The '.if' part was assembled, so skip the '.else' part
+						cursor.next_token();
+						let (end, is_else) = skip_conditional(&tokens[idx..], cursor.pos, false);
+						let end = end.ok_or(in_context("'.else' without '.endif'".to_owned()))?;
+						if is_else
+						{
+							return Err(in_context("'.else' after '.else'".to_owned()));
+						}
+						replace_consumed(&mut tokens, idx, end, Vec::new());
+					},
+				}
+			},
+			".endif" =>
+			{
+				conditionals
+					.pop()
+					.ok_or(in_context("'.endif' without '.if'".to_owned()))?;
+				idx += 1;
+			},
+			text =>
+			{
+				let name = text.split('(').next().unwrap();
+				let mac = if cursor.peek_next() == Some(":")
+				{
+					None
+				}
+				else
+				{
+					macros.get(name)
+				};
+
+				if let Some(mac) = mac
+				{
+					cursor.bump(name.len());
+					let args = parse_macro_arguments(&mut cursor, name, &mac.params)
+						.map_err(in_context)?;
+					let expansion = expansions
+						.push(name, token.expansion)
+						.map_err(|err| expansions.backtrace(err, Some(token)))?;
+
+					// Expanded tokens are processed next, so nested macros are expanded too
+					let expanded = expand_body(&mac.body, &args, Some(expansion), Some(expansion));
+					let pos = cursor.pos;
+					replace_consumed(&mut tokens, idx, pos, expanded);
+				}
+				else
+				{
+					// Keep track of labels for '.ifdef'
+					if let Some(label) = text.strip_suffix(':')
+					{
+						let label = if label.is_empty()
+						{
+							result.last().map_or("", Token::as_str)
+						}
+						else
+						{
+							label
+						};
+						// Uses of the label would be expanded instead
+						if macros.contains_key(label)
+						{
+							return Err(in_context(format!(
+								"Label '{}' named like a macro",
+								label
+							)));
+						}
+						labels.insert(label.to_owned());
+					}
+					result.push(token.clone());
+					idx += 1;
+				}
+			},
+		}
+	}
+	if !conditionals.is_empty()
+	{
+		return Err("'.if' without '.endif'".to_owned());
+	}
+	Ok(result)
+}
+
+/// Replaces the tokens consumed by a cursor, which started at `idx`, with the
+/// given tokens.
+///
+/// Anything not consumed from the last token is kept.
+fn replace_consumed(tokens: &mut Vec<Token>, idx: usize, pos: Pos, mut replacement: Vec<Token>)
+{
+	let mut end = idx + pos.idx;
+	if pos.offset > 0
+	{
+		replacement.push(Token {
+			text: tokens[end].text[pos.offset..].to_owned(),
+			expansion: tokens[end].expansion,
+		});
+		end += 1;
+	}
+	tokens.splice(idx..end, replacement);
+}
+
+/// Finds the end of the current part of a conditional block, starting from
+/// the given position.
+///
+/// Returns the position after the `.endif` or `.else` (if allowed) ending the
+/// part, or `None` if there is none, and whether it was an `.else`.
+fn skip_conditional(tokens: &[Token], start: Pos, allow_else: bool) -> (Option<Pos>, bool)
+{
+	let mut depth = 0;
+	for (idx, token) in tokens
+		.iter()
+		.enumerate()
+		.skip(start.idx + (start.offset > 0) as usize)
+	{
+		let end = Some(Pos {
+			idx: idx + 1,
+			offset: 0,
+		});
+		match token.as_str()
+		{
+			".if" | ".ifdef" | ".ifndef" => depth += 1,
+			".endif" if depth == 0 => return (end, false),
+			".endif" => depth -= 1,
+			".else" if depth == 0 && allow_else => return (end, true),
+			_ => (),
+		}
+	}
+	(None, false)
+}
+
+/// Parses a macro definition following `.macro`, up to and including `.endm`.
+///
+/// Returns the name of the macro and the macro.
+fn parse_macro_definition(cursor: &mut Cursor) -> Result<(String, Macro), String>
+{
+	let name = cursor.identifier()?.to_owned();
+	let mut params: Vec<(String, Option<String>)> = Vec::new();
+	if cursor.eat("(") && !cursor.eat(")")
+	{
+		loop
+		{
+			let param = cursor.identifier()?.to_owned();
+			let default = if cursor.eat("=")
+			{
+				Some(parse_argument(cursor, true)?)
+			}
+			else
+			{
+				None
+			};
+			if params.iter().any(|(p, _)| *p == param)
+			{
+				return Err(format!(
+					"Parameter '{}' defined twice for macro '{}'",
+					param, name
+				));
+			}
+			params.push((param, default));
+
+			if cursor.eat(")")
+			{
+				break;
+			}
+			else if !cursor.eat(",")
+			{
+				return Err(format!(
+					"Expected ',' or ')': {}",
+					cursor.current().unwrap_or("")
+				));
+			}
+		}
+	}
+
+	let body = parse_body(cursor, &[".macro"], ".endm")
+		.map_err(|_| format!("Macro '{}' is missing '.endm'", name))?;
+	Ok((name, Macro { params, body }))
+}
+
+/// Parses the operands of `.rept`.
+///
+/// Returns the bindings for each iteration.
+fn parse_rept(
+	cursor: &mut Cursor,
+	defines: &HashMap<String, i128>,
+) -> Result<Vec<Vec<(String, String)>>, String>
+{
+	let count = Expr::parse(cursor)?;
+	let count = count
+		.evaluate(&|sym| {
+			defines
+				.get(sym)
+				.cloned()
+				.ok_or(format!("Unknown constant: {}", sym))
+		})
+		.and_then(|value| u64::try_from(value).map_err(|_| format!("Invalid count: {}", count)))?;
+	if count > MAX_REPETITION_TOKENS as u64
+	{
+		return Err(format!(
+			"Repetitions too large (maximum {} tokens)",
+			MAX_REPETITION_TOKENS
+		));
+	}
+	let index = if cursor.eat(",")
+	{
+		Some(cursor.identifier()?)
+	}
+	else
+	{
+		None
+	};
+	Ok((0..count)
+		.map(|i| {
+			index
+				.iter()
+				.map(|index| (index.to_string(), i.to_string()))
+				.collect()
+		})
+		.collect())
+}
+
+/// Parses the operands of `.irp`.
+///
+/// Returns the bindings for each iteration.
+fn parse_irp(cursor: &mut Cursor) -> Result<Vec<Vec<(String, String)>>, String>
+{
+	let sym = cursor.identifier()?;
+	let mut iterations = Vec::new();
+	while cursor.eat(",")
+	{
+		iterations.push(vec![(sym.to_owned(), parse_argument(cursor, false)?)]);
+	}
+	Ok(iterations)
+}
+
+/// Consumes all tokens until the given closing directive, including it.
+///
+/// Nested blocks, started by any of the given opening directives, are
+/// included in the body.
+///
+/// Returns the tokens before the closing directive.
+fn parse_body(cursor: &mut Cursor, opening: &[&str], closing: &str) -> Result<Vec<String>, String>
+{
+	let mut body = Vec::new();
+	let mut depth = 0;
+	loop
+	{
+		match cursor.next_token()
+		{
+			None => return Err(format!("Missing '{}'", closing)),
+			Some(tok) if tok == closing && depth == 0 => break,
+			Some(tok) =>
+			{
+				if opening.contains(&tok)
+				{
+					depth += 1;
+				}
+				else if tok == closing
+				{
+					depth -= 1;
+				}
+				body.push(tok.to_owned());
+			},
+		}
+	}
+	Ok(body)
+}
+
+/// Produces the tokens of one expansion of the given body, substituting the
+/// given bindings.
+fn expand_body(
+	body: &[String],
+	bindings: &[(String, String)],
+	unique: Option<usize>,
+	expansion: Option<usize>,
+) -> Vec<Token>
+{
+	body.iter()
+		.flat_map(|body_token| {
+			let text = substitute(body_token, bindings, unique);
+			tokenize(std::iter::once(text.as_str()))
+				.into_iter()
+				.map(|text| {
+					Token {
+						text: text.to_owned(),
+						expansion,
+					}
+				})
+				.collect::<Vec<_>>()
+		})
+		.collect()
+}
+
+/// Parses the arguments of a macro invocation, following the macro name.
+///
+/// Returns the value of each parameter.
+fn parse_macro_arguments(
+	cursor: &mut Cursor,
+	name: &str,
+	params: &[(String, Option<String>)],
+) -> Result<Vec<(String, String)>, String>
+{
+	let mut args = Vec::new();
+	if cursor.eat("(") && !cursor.eat(")")
+	{
+		loop
+		{
+			args.push(parse_argument(cursor, true)?);
+			if cursor.eat(")")
+			{
+				break;
+			}
+			else if !cursor.eat(",")
+			{
+				return Err(format!(
+					"Expected ',' or ')': {}",
+					cursor.current().unwrap_or("")
+				));
+			}
+		}
+	}
+
+	let mut values = vec![None; params.len()];
+	let mut positional = 0;
+	for arg in args
+	{
+		let key_len = arg
+			.find(|c: char| !(c.is_alphanumeric() || c == '_'))
+			.unwrap_or(arg.len());
+		let keyword = Some(&arg[key_len..])
+			.filter(|rest| key_len > 0 && rest.starts_with('=') && !rest.starts_with("=>"))
+			.map(|rest| (&arg[..key_len], rest[1..].trim()));
+
+		let (param, value) = if let Some((key, value)) = keyword
+		{
+			let param = params
+				.iter()
+				.position(|(p, _)| p == key)
+				.ok_or(format!("Macro '{}' has no parameter '{}'", name, key))?;
+			(param, value)
+		}
+		else
+		{
+			positional += 1;
+			(positional - 1, arg.as_str())
+		};
+		*values
+			.get_mut(param)
+			.ok_or(format!("Too many arguments for macro '{}'", name))? = Some(value.to_owned());
+	}
+
+	params
+		.iter()
+		.zip(values)
+		.map(|((param, default), value)| {
+			value
+				.or(default.clone())
+				.map(|value| (param.clone(), value))
+				.ok_or(format!("Missing argument '{}' for macro '{}'", param, name))
+		})
+		.collect()
+}
+
+/// Consumes an argument: everything until a `,` or `)` that isn't inside
+/// parentheses or a string.
+///
+/// Unless the argument is itself inside parentheses, it also ends with the
+/// current token.
+fn parse_argument(cursor: &mut Cursor, in_parentheses: bool) -> Result<String, String>
+{
+	let mut arg = String::new();
+	let mut depth = 0;
+	while let Some(tok) = cursor.current()
+	{
+		let mut in_string = false;
+		let mut escaped = false;
+		let end = tok.char_indices().find(|(_, c)| {
+			match c
+			{
+				'"' if !escaped => in_string = !in_string,
+				'(' if !in_string => depth += 1,
+				')' | ',' if !in_string && depth == 0 => return true,
+				')' if !in_string => depth -= 1,
+				_ => (),
+			}
+			escaped = *c == '\\' && !escaped;
+			false
+		});
+
+		if let Some((end, _)) = end
+		{
+			arg.push_str(&tok[..end]);
+			cursor.bump(end);
+			return Ok(arg.trim_end().to_owned());
+		}
+		arg.push_str(tok);
+		cursor.next_token();
+		if !in_parentheses && depth == 0
+		{
+			return Ok(arg);
+		}
+		arg.push(' ');
+	}
+	Err("Unterminated arguments".to_owned())
+}
+
+/// Replaces any `\name` in the text with the value bound to `name`, and `\@`
+/// with the given unique number, if any.
+fn substitute(text: &str, bindings: &[(String, String)], unique: Option<usize>) -> String
+{
+	let mut result = String::new();
+	let mut rest = text;
+	while let Some(idx) = rest.find('\\')
+	{
+		result.push_str(&rest[..idx]);
+		rest = &rest[idx + 1..];
+
+		if let Some((unique, after)) = unique.zip(rest.strip_prefix('@'))
+		{
+			result.push_str(&unique.to_string());
+			rest = after;
+			continue;
+		}
+
+		let len = rest
+			.find(|c: char| !(c.is_alphanumeric() || c == '_'))
+			.unwrap_or(rest.len());
+		if let Some((_, value)) = bindings
+			.iter()
+			.find(|(name, _)| len > 0 && name == &rest[..len])
+		{
+			result.push_str(value);
+			rest = &rest[len..];
+		}
+		else
+		{
+			result.push('\\');
+		}
+	}
+	result.push_str(rest);
+	result
+}
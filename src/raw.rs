@@ -1,89 +1,83 @@
-use crate::assemble::Assemble;
+use crate::{
+	assemble::Assemble,
+	checksum::Checksum,
+	expr::{
+		bit_mask, expression_index, extract_expressions, is_covered, restore_expressions, Expr,
+	},
+	labels::{restore_labels, restore_references, scope_labels},
+	lexer::{normalize_literals, tokenize, Cursor, Token},
+	loader::{FileLoader, Loader},
+	object::{
+		section_offsets, Label, Object, Reference, Relocation, RelocationField, Section,
+		SectionFlags, SymbolKind, Visibility,
+	},
+	preprocess::{preprocess, Expansions},
+};
 use byteorder::{LittleEndian, WriteBytesExt};
 use regex::Regex;
 use scry_isa::{
-	Arrow, CanConsume, Comma, Instruction, Keyword, Maybe, ParseError, ParseErrorType, Parser,
-	Resolve, Symbol, Then, Type, TypeMatcher,
+	Arrow, CallVariant, CanConsume, Comma, Instruction, Keyword, Maybe, ParseError,
+	ParseErrorType, Parser, Resolve, Symbol, Then, Type, TypeMatcher,
+};
+use std::{
+	borrow::Borrow,
+	cell::RefCell,
+	collections::{HashMap, HashSet},
 };
-use std::{borrow::Borrow, collections::HashMap, iter::Peekable};
 
 /// An assembler/disassembler for raw assembly.
 ///
 /// "Raw" assembly contains only instructions and nothing else.
-/// For text assembly, this includes label declarations and uses but nothing
-/// else. For machine code, only instructions can be present.
-pub struct Raw {}
-
-#[derive(Clone)]
-struct GroupIter<'a, I: Clone + Iterator<Item = &'a str>, const EMIT_LABEL: bool>
+/// For text assembly, this includes label declarations and uses, and
+/// directives that produce raw bytes. For machine code, only instructions can
+/// be present.
+///
+/// Wherever a label can be used, a constant expression in braces can be used
+/// instead, e.g. `{table_end - table + 2}`.
+/// `.` and `$` are the address of the current instruction or data, with or
+/// without braces.
+pub struct Raw
 {
-	iter: Peekable<I>,
+	/// Used to read any files the assembly refers to.
+	pub loader: Box<dyn Loader>,
+	/// Constants available to conditional assembly and `.rept` counts.
+	pub defines: HashMap<String, i128>,
+	/// The address the assembled bytes are loaded at.
+	///
+	/// Label addresses and `.` are relative to this, while distances between
+	/// labels are unaffected.
+	pub origin: u64,
+	/// Whether to assemble an object for linking.
+	///
+	/// All undefined symbols are treated as imports instead of failing, and
+	/// references to them are recorded as [`Relocation`]s.
+	/// So are references to label addresses and distances between sections,
+	/// such that the linker can place each section anywhere.
+	/// Brace expressions can only use constants, not labels or `.`, as their
+	/// values would change when linking.
+	/// Checksums can't be used, as the linker would change what they cover.
+	pub relocatable: bool,
+	/// Whether to put each function declared using `.func` in its own section.
+	///
+	/// The section is named after the function and the section it is declared
+	/// in, e.g. `.text.main`, such that the linker can discard the function if
+	/// it isn't used.
+	/// Private functions with the same name in different files get a numeric
+	/// suffix, e.g. `.text.helper.1`.
+	pub function_sections: bool,
 }
-impl<'a, I: Clone + Iterator<Item = &'a str>, const EMIT_LABEL: bool> Iterator
-	for GroupIter<'a, I, EMIT_LABEL>
-{
-	type Item = Group<'a, I, EMIT_LABEL>;
 
-	fn next(&mut self) -> Option<Self::Item>
-	{
-		if let Some(_) = self.iter.peek()
-		{
-			let group_iter = Some(Group {
-				done: false,
-				iter: self.iter.clone(),
-			});
-
-			// Skip all items until we see ":"
-			while let Some(next) = self.iter.next()
-			{
-				if next.ends_with(":")
-				{
-					break;
-				}
-			}
-
-			group_iter
-		}
-		else
-		{
-			None
-		}
-	}
-}
-
-#[derive(Clone, Debug)]
-struct Group<'a, I: Clone + Iterator<Item = &'a str>, const EMIT_LABEL: bool>
-{
-	done: bool,
-	iter: Peekable<I>,
-}
-impl<'a, I: Clone + Iterator<Item = &'a str>, const EMIT_LABEL: bool> Iterator
-	for Group<'a, I, EMIT_LABEL>
+impl Default for Raw
 {
-	type Item = I::Item;
-
-	fn next(&mut self) -> Option<Self::Item>
+	fn default() -> Self
 	{
-		if !self.done
-		{
-			if let Some(item) = self.iter.next()
-			{
-				return if let Some(idx) = item.find(":")
-				{
-					self.done = true;
-					Some(item.split_at(idx).0)
-                        // Only output the label if instructed
-                        .filter(|_| EMIT_LABEL)
-                        // Don't output empty string
-                        .filter(|s| !s.is_empty())
-				}
-				else
-				{
-					Some(item)
-				};
-			}
+		Self {
+			loader: Box::new(FileLoader::default()),
+			defines: HashMap::new(),
+			origin: 0,
+			relocatable: false,
+			function_sections: false,
 		}
-		None
 	}
 }
 
@@ -96,6 +90,7 @@ impl Keyword for DirBytesKeyword
 fn parse_bytes_direcive<'a, F, B>(
 	mut iter: impl Iterator<Item = &'a str> + Clone,
 	f: B,
+	resolve: impl Fn(Resolve<'a>) -> Result<i128, &'a str>,
 ) -> Result<(Vec<u8>, CanConsume), String>
 where
 	B: Borrow<F>,
@@ -119,11 +114,11 @@ where
 			.and_then(|((sym1, sym2), consumed2)| {
 				if let Some((_, sym2)) = sym2
 				{
-					f(Resolve::Distance(sym1, sym2))
+					resolve(Resolve::Distance(sym1, sym2))
 				}
 				else
 				{
-					f(Resolve::Address(sym1))
+					resolve(Resolve::Address(sym1))
 				}
 				.map_err(|_| {
 					ParseError::from_consumed(consumed2.clone(), ParseErrorType::UnknownSymbol)
@@ -135,7 +130,6 @@ where
 			if signed
 			{
 				parsed_ref
-					.map(|(val, consumed)| (val as i128, consumed))
 					.or_else(|_| {
 						<i128 as Parser>::parse::<_, F, _>(
 							next_token.clone().into_iter().chain(iter.clone()),
@@ -144,8 +138,8 @@ where
 					})
 					.map_err(|err| format!("{:?}", err))
 					.and_then(|(val, consumed2)| {
-						let min_value = (2i128.pow((size * 8) - 1) * (-1)) - 1;
-						let max_value = 2i128.pow((size * 8) - 1);
+						let min_value = i128::MIN >> (128 - size * 8);
+						let max_value = i128::MAX >> (128 - size * 8);
 
 						if min_value <= val && max_value >= val
 						{
@@ -165,77 +159,610 @@ where
 			}
 			else
 			{
+				// Negative values of references are kept, such that errors show them
 				parsed_ref
-					.map(|(val, consumed)| (val as u128, consumed))
+					.map(|(val, consumed)| (u128::try_from(val).map_err(|_| val), consumed))
 					.or_else(|_| {
 						<u128 as Parser>::parse::<_, F, _>(
 							next_token.clone().into_iter().chain(iter.clone()),
 							f,
 						)
+						.map(|(val, consumed)| (Ok(val), consumed))
 					})
 					.map_err(|err| format!("{:?}", err))
 					.and_then(|(val, consumed2)| {
-						let max_value = 2u128.pow(size * 8);
+						let max_value = u128::MAX >> (128 - size * 8);
 
-						if max_value >= val
-						{
-							Ok((
-								val.to_le_bytes().into_iter().take(size as usize).collect(),
-								consumed.then(&consumed2),
-							))
-						}
-						else
+						match val
 						{
-							Err(format!(
-								"Bytes value out of bounds (actual, minimum, maximum): {}, {}, {}",
-								val, 0, max_value
-							))
+							Ok(val) if max_value >= val =>
+							{
+								Ok((
+									val.to_le_bytes().into_iter().take(size as usize).collect(),
+									consumed.then(&consumed2),
+								))
+							},
+							_ =>
+							{
+								Err(format!(
+									"Bytes value out of bounds (actual, minimum, maximum): {}, \
+									 {}, {}",
+									val.map_or_else(|val| val.to_string(), |val| val.to_string()),
+									0,
+									max_value
+								))
+							},
 						}
 					})
 			}
 		})
 }
 
-impl Assemble for Raw
+struct DirIncbinKeyword();
+impl Keyword for DirIncbinKeyword
 {
-	type Error = String;
+	const WORD: &'static str = ".incbin";
+}
 
-	fn assemble<'a, I>(asm: I) -> Result<Vec<u8>, Self::Error>
+/// Parses the operands of an `.incbin` directive: the file name, followed by an
+/// optional offset and length.
+fn parse_incbin_directive(cursor: &mut Cursor) -> Result<(String, u64, Option<u64>), String>
+{
+	let file = cursor.string()?;
+	let mut bounds = Vec::new();
+	while bounds.len() < 2 && cursor.eat(",")
+	{
+		bounds.push(cursor.integer()?);
+	}
+	Ok((
+		file,
+		bounds.first().cloned().unwrap_or(0),
+		bounds.get(1).cloned(),
+	))
+}
+
+/// A directive that checks the assembly instead of producing bytes.
+enum Diagnostic
+{
+	/// `.assert expr[, "message"]`, checked once labels are resolved.
+	Assert(Expr, Option<String>),
+	/// `.error "message"`
+	Error(String),
+	/// `.warning "message"`
+	Warning(String),
+}
+
+/// If a diagnostic directive is at the cursor, consumes and returns it.
+fn parse_diagnostic(cursor: &mut Cursor) -> Result<Option<Diagnostic>, String>
+{
+	let directive = cursor.current();
+	match directive
+	{
+		Some(".assert") =>
+		{
+			cursor.next_token();
+			let expr = Expr::parse(cursor)?;
+			let message = if cursor.eat(",")
+			{
+				Some(cursor.string()?)
+			}
+			else
+			{
+				None
+			};
+			Ok(Some(Diagnostic::Assert(expr, message)))
+		},
+		Some(".error") | Some(".warning") =>
+		{
+			cursor.next_token();
+			let message = cursor.string()?;
+			Ok(Some(
+				if directive == Some(".error")
+				{
+					Diagnostic::Error(message)
+				}
+				else
+				{
+					Diagnostic::Warning(message)
+				},
+			))
+		},
+		_ => Ok(None),
+	}
+}
+
+/// If a section directive is at the cursor, consumes it and returns the name
+/// of the section to switch to, with its flags if given.
+///
+/// `.text`, `.data` and `.bss` switch to the section of the same name, while
+/// `.section name[, "flags"]` switches to any section.
+/// The flags are any of `w` (writable), `x` (executable) and `b` (no
+/// contents).
+fn parse_section_directive<'t>(
+	cursor: &mut Cursor<'t>,
+) -> Result<Option<(&'t str, Option<SectionFlags>)>, String>
+{
+	match cursor.current()
+	{
+		Some(name @ (".text" | ".data" | ".bss")) =>
+		{
+			cursor.next_token();
+			Ok(Some((name, None)))
+		},
+		Some(".section") =>
+		{
+			cursor.next_token();
+			let tok = cursor.current().unwrap_or("");
+			let len = tok
+				.find(|c: char| !(c.is_alphanumeric() || c == '_' || c == '.'))
+				.unwrap_or(tok.len());
+			if len == 0
+			{
+				return Err(format!("Expected section name: {}", tok));
+			}
+			cursor.bump(len);
+			let flags = if cursor.eat(",")
+			{
+				let mut flags = SectionFlags::default();
+				for c in cursor.string()?.chars()
+				{
+					match c
+					{
+						'w' => flags.write = true,
+						'x' => flags.execute = true,
+						'b' => flags.no_bits = true,
+						_ => return Err(format!("Unknown section flag: {}", c)),
+					}
+				}
+				Some(flags)
+			}
+			else
+			{
+				None
+			};
+			Ok(Some((&tok[..len], flags)))
+		},
+		_ => Ok(None),
+	}
+}
+
+/// If a symbol declaration is at the cursor, consumes it and returns the
+/// directive together with the declared symbols.
+///
+/// `.global`, `.local` and `.extern` each take a comma-separated list of
+/// symbols.
+fn parse_symbol_directive<'t>(
+	cursor: &mut Cursor<'t>,
+) -> Result<Option<(&'t str, Vec<&'t str>)>, String>
+{
+	match cursor.current()
+	{
+		Some(directive @ (".global" | ".local" | ".extern")) =>
+		{
+			cursor.next_token();
+			let mut symbols = vec![cursor.identifier()?];
+			while cursor.eat(",")
+			{
+				symbols.push(cursor.identifier()?);
+			}
+			Ok(Some((directive, symbols)))
+		},
+		_ => Ok(None),
+	}
+}
+
+/// If a checksum directive is at the cursor, consumes it and returns the
+/// checksum with the start and end of the range it covers.
+fn parse_checksum_directive(cursor: &mut Cursor) -> Result<Option<(Checksum, Expr, Expr)>, String>
+{
+	if let Some(checksum) = cursor.current().and_then(Checksum::from_directive)
+	{
+		cursor.next_token();
+		let start = Expr::parse(cursor)?;
+		if !cursor.eat(",")
+		{
+			return Err(format!("Expected ',': {}", cursor.current().unwrap_or("")));
+		}
+		Ok(Some((checksum, start, Expr::parse(cursor)?)))
+	}
+	else
+	{
+		Ok(None)
+	}
+}
+
+/// The byte order of data values.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Endianness
+{
+	/// Least significant byte first, like instructions.
+	Little,
+	/// Most significant byte first.
+	Big,
+}
+
+/// If an `.endian big` or `.endian little` directive is at the cursor,
+/// consumes it and returns the endianness it switches to.
+///
+/// The endianness only applies to data directives, never to instructions.
+fn parse_endian_directive(cursor: &mut Cursor) -> Result<Option<Endianness>, String>
+{
+	if cursor.current() != Some(".endian")
+	{
+		return Ok(None);
+	}
+	cursor.next_token();
+	match cursor.identifier()?
+	{
+		"big" => Ok(Some(Endianness::Big)),
+		"little" => Ok(Some(Endianness::Little)),
+		endianness => Err(format!("Unknown endianness: {}", endianness)),
+	}
+}
+
+/// A directive that describes a symbol.
+enum Metadata<'t>
+{
+	/// `.type name, @function` or `.type name, @object`
+	Type(&'t str, SymbolKind),
+	/// `.size name, expr`, evaluated once labels are resolved.
+	Size(&'t str, Expr),
+	/// `.func name`, which declares the label and starts the function.
+	Func(&'t str),
+	/// `.endfunc`, which ends the function, giving it its size.
+	EndFunc,
+}
+
+/// If a symbol metadata directive is at the cursor, consumes and returns it.
+fn parse_metadata_directive<'t>(cursor: &mut Cursor<'t>) -> Result<Option<Metadata<'t>>, String>
+{
+	let metadata = match cursor.current()
+	{
+		Some(".type") =>
+		{
+			cursor.next_token();
+			let sym = cursor.identifier()?;
+			if !cursor.eat(",")
+			{
+				return Err(format!("Expected ',': {}", cursor.current().unwrap_or("")));
+			}
+			cursor.eat("@");
+			let kind = match cursor.identifier()?
+			{
+				"function" => SymbolKind::Function,
+				"object" => SymbolKind::Object,
+				kind => return Err(format!("Unknown symbol type: {}", kind)),
+			};
+			Metadata::Type(sym, kind)
+		},
+		Some(".size") =>
+		{
+			cursor.next_token();
+			let sym = cursor.identifier()?;
+			if !cursor.eat(",")
+			{
+				return Err(format!("Expected ',': {}", cursor.current().unwrap_or("")));
+			}
+			Metadata::Size(sym, Expr::parse(cursor)?)
+		},
+		Some(".func") =>
+		{
+			cursor.next_token();
+			Metadata::Func(cursor.identifier()?)
+		},
+		Some(".endfunc") =>
+		{
+			cursor.next_token();
+			Metadata::EndFunc
+		},
+		_ => return Ok(None),
+	};
+	Ok(Some(metadata))
+}
+
+/// Adds a label declared in the given file, unless it is already declared.
+fn declare_label<'t>(
+	labels: &mut Vec<Label>,
+	label_indices: &mut HashMap<&'t str, usize>,
+	name: &'t str,
+	(section, offset): (usize, usize),
+	file: Option<&str>,
+) -> Result<(), String>
+{
+	if let Some(first) = label_indices.get(name)
+	{
+		return Err(match &labels[*first].file
+		{
+			Some(file) => format!("'{}' defined twice, first in '{}'", name, file),
+			None => format!("'{}' defined twice", name),
+		});
+	}
+	label_indices.insert(name, labels.len());
+	labels.push(Label {
+		name: name.to_owned(),
+		section,
+		offset,
+		visibility: Visibility::Local,
+		kind: SymbolKind::Unknown,
+		size: None,
+		file: file.map(str::to_owned),
+	});
+	Ok(())
+}
+
+/// The name of the section a function gets if declared in the given section.
+fn function_section(section: &str, function: &str) -> String
+{
+	format!("{}.{}", section, function)
+}
+
+/// The kind of field the reference is in, in the given instruction.
+///
+/// `moved` parses the instruction again, with the reference's value moved by the
+/// given number of bytes, to tell which operand the reference is in.
+fn instruction_field(
+	instr: &Instruction,
+	moved: impl Fn(i128) -> Option<Instruction>,
+) -> RelocationField
+{
+	match instr
+	{
+		Instruction::Constant(..) => RelocationField::ConstImmediate,
+		Instruction::Call(CallVariant::Ret, _) => RelocationField::JumpLocation,
+		Instruction::Call(..) => RelocationField::JumpTarget,
+		Instruction::Jump(_, location) =>
+		{
+			// Moving the value may put it out of range in one direction
+			match [2, -2].into_iter().find_map(moved)
+			{
+				Some(Instruction::Jump(_, moved)) if moved != *location =>
+				{
+					RelocationField::JumpLocation
+				},
+				_ => RelocationField::JumpTarget,
+			}
+		},
+		// All other instructions only refer to symbols by output offsets
+		_ => RelocationField::OutputOffset,
+	}
+}
+
+/// The symbol that failed to resolve, as returned to `scry_isa`.
+fn resolved_symbol<'a>(to_resolve: &Resolve<'a>) -> &'a str
+{
+	match to_resolve
+	{
+		Resolve::Address(sym) | Resolve::DistanceCurrent(sym) => *sym,
+		Resolve::Distance(_, sym) => *sym,
+	}
+}
+
+/// Adds the file a message comes from to it, if known.
+fn with_file(mut msg: String, file: Option<&str>) -> String
+{
+	if let Some(file) = file
+	{
+		msg.push_str(&format!("\nIn file '{}'", file));
+	}
+	msg
+}
+
+/// Returns the name of the file containing the token at the given index.
+fn file_of<'a>(files: &[(usize, &'a str)], idx: usize) -> Option<&'a str>
+{
+	files
+		.iter()
+		.rfind(|(start, _)| *start <= idx)
+		.map(|(_, name)| *name)
+}
+
+/// Whether the cursor is at the start of one of the given files.
+fn at_file_start(files: &[(usize, &str)], cursor: &Cursor) -> bool
+{
+	cursor.pos.offset == 0 && files.iter().any(|(start, _)| *start == cursor.pos.idx)
+}
+
+/// Returns the index of the section with the given name, adding it if it
+/// doesn't exist yet.
+fn switch_section(
+	sections: &mut Vec<Section>,
+	name: &str,
+	flags: Option<SectionFlags>,
+) -> Result<usize, String>
+{
+	if let Some(idx) = sections.iter().position(|s| s.name == name)
+	{
+		if flags.is_some_and(|flags| flags != sections[idx].flags)
+		{
+			return Err(format!(
+				"Section '{}' redeclared with different flags",
+				name
+			));
+		}
+		Ok(idx)
+	}
+	else
+	{
+		let flags = flags.unwrap_or(SectionFlags::default_for(name));
+		sections.push(Section::new(name, flags));
+		Ok(sections.len() - 1)
+	}
+}
+
+impl Raw
+{
+	/// Assembles the given assembly using this assembler's configuration.
+	///
+	/// The assembly can't refer to symbols defined elsewhere, nor be
+	/// [relocatable](Raw::relocatable), as that requires linking the object
+	/// given by [`Raw::assemble_object`].
+	pub fn assemble_with<'a, I>(&self, asm: I) -> Result<Vec<u8>, String>
 	where
 		I: Iterator<Item = &'a str> + Clone,
 	{
-		let cleaned = asm
-            // Remove comments
-            .flat_map(|mut s| {
-                let mut result = Vec::new();
-                while let Some((before, after)) = s.split_once(';') {
-                    // Keep anything before comment
-                    result.push(before);
-                    // Now check anything after first newline
-                    s = after
-                        .split_once(&['\r', '\n'])
-                        .map_or("", |(_, after_newline)| after_newline);
-                }
-                // The remaining cannot have comments
-                result.push(s);
-                result.into_iter()
-            })
-            // Remove whitespace
-            .flat_map(|s| s.split(char::is_whitespace))
-            .filter(|s| !s.is_empty())
-            // We split all tokens after ":", so we can recognize the end of a group
-            .flat_map(|s| s.split_inclusive(":"))
-            .peekable();
-
-		let mut clean_peek = cleaned.clone().peekable();
-		let mut label_addresses: HashMap<&'a str, i32> = HashMap::new();
-		let mut byte_count = 0;
+		let object = self.assemble_object(asm)?;
+		if let Some(import) = object.imports.first()
+		{
+			return Err(format!("Unknown label: {}", import));
+		}
+		if !object.relocations.is_empty()
+		{
+			return Err("Relocatable object can't be assembled without linking".to_owned());
+		}
+		Ok(object.bytes())
+	}
+
+	/// Assembles the given assembly using this assembler's configuration,
+	/// returning each section and label separately, together with any
+	/// warnings.
+	pub fn assemble_object<'a, I>(&self, asm: I) -> Result<Object, String>
+	where
+		I: Iterator<Item = &'a str> + Clone,
+	{
+		self.assemble_sources([(None, tokenize(asm))])
+	}
+
+	/// Assembles the given source files, each given by its name and contents,
+	/// into a single object.
+	///
+	/// The files are assembled in the given order, as if concatenated, except
+	/// that each file starts in `.text` with little endianness, and that
+	/// macros, conditional assembly and labels not declared `.global` are
+	/// private to their file.
+	/// Errors and warnings name the file they come from, and the labels of the
+	/// object record the file declaring them.
+	pub fn assemble_files(&self, files: &[(&str, &str)]) -> Result<Object, String>
+	{
+		self.assemble_sources(
+			files
+				.iter()
+				.map(|(name, asm)| (Some(*name), tokenize(std::iter::once(*asm)))),
+		)
+	}
+
+	/// Assembles the tokens of each source, which is named if it is a file.
+	fn assemble_sources<'a, I>(&self, sources: I) -> Result<Object, String>
+	where
+		I: IntoIterator<Item = (Option<&'a str>, Vec<&'a str>)>,
+	{
+		let mut expansions = Expansions::default();
+		let mut names = Vec::new();
+		let mut expressions = Vec::new();
+		let mut tokens = Vec::new();
+		// The index of the first token of each file, with its name
+		let mut files = Vec::new();
+		for (file, source) in sources
+		{
+			let in_file = |err: String| with_file(err, file);
+			let source = source
+				.into_iter()
+				.map(|token| normalize_literals(token).map(|text| Token::from(text.as_str())))
+				.collect::<Result<_, _>>()
+				.map_err(in_file)?;
+			let source = preprocess(source, &self.defines, &mut expansions).map_err(in_file)?;
+			let source = scope_labels(source, &mut names, file.is_some()).map_err(in_file)?;
+			let source = extract_expressions(source, &mut expressions)
+				.map_err(|err| in_file(restore_labels(&err, &names)))?;
+			if let Some(file) = file
+			{
+				files.push((tokens.len(), file));
+			}
+			tokens.extend(source);
+		}
+
+		// Adds the macro expansions and file of the given statement to a message
+		let trace = |msg: &str, statement: usize| {
+			with_file(
+				expansions.backtrace(restore_labels(msg, &names), tokens.get(statement)),
+				file_of(&files, statement),
+			)
+		};
+		let mut statement = 0;
+		let mut warnings = Vec::new();
+		let mut object = self
+			.assemble_tokens(
+				&tokens,
+				&expressions,
+				&names,
+				&files,
+				&mut statement,
+				&mut warnings,
+			)
+			.map_err(|err| trace(&err, statement))?;
+		object.warnings = warnings
+			.into_iter()
+			.map(|(statement, warning)| trace(&warning, statement))
+			.collect();
+		for label in object.labels.iter_mut()
+		{
+			label.name = restore_labels(&label.name, &names);
+		}
+		for idx in 0..object.sections.len()
+		{
+			// Private functions in different files may have the same name, but
+			// their sections must still be told apart
+			let name = restore_labels(&object.sections[idx].name, &names);
+			let mut unique = name.clone();
+			let mut count = 0;
+			while object.sections[..idx].iter().any(|s| s.name == unique)
+			{
+				count += 1;
+				unique = format!("{}.{}", name, count);
+			}
+			object.sections[idx].name = unique;
+		}
+		for import in object.imports.iter_mut()
+		{
+			*import = restore_labels(import, &names);
+		}
+		Ok(object)
+	}
+
+	/// Assembles preprocessed tokens.
+	///
+	/// `statement` is kept updated with the index of the first token of the
+	/// statement being assembled, such that errors can be traced back to it.
+	/// Warnings are added to `warnings` together with the index of their
+	/// statement.
+	///
+	/// Returns the assembled object, without the warnings.
+	/// For resolving addresses, sections are laid out one after the other, in
+	/// order.
+	/// Until linked, references to imported symbols are assembled as if the
+	/// symbol was directly after the referring instruction.
+	///
+	/// `sources` gives the index of the first token of each file, if the
+	/// tokens come from multiple files.
+	/// `names` are the full names of the labels, with which relocations are
+	/// recorded.
+	fn assemble_tokens<'t>(
+		&self,
+		tokens: &'t [Token],
+		expressions: &[Expr],
+		names: &[String],
+		sources: &[(usize, &str)],
+		statement: &mut usize,
+		warnings: &mut Vec<(usize, String)>,
+	) -> Result<Object, String>
+	{
+		let mut visibilities: HashMap<&str, Visibility> = HashMap::new();
+		let mut externs: Vec<&str> = Vec::new();
+		let mut kinds: HashMap<&str, SymbolKind> = HashMap::new();
+		let mut sizes = Vec::new();
+		// The function being declared using '.func', its section and the
+		// section it was declared in
+		let mut function = None;
+		let mut files = HashMap::new();
+		let mut labels: Vec<Label> = Vec::new();
+		let mut label_indices: HashMap<&str, usize> = HashMap::new();
+		let mut sections = vec![Section::new(".text", SectionFlags::default_for(".text"))];
+		let mut current = 0;
 
 		let mnems_pat = scry_isa::INSTRUCTION_MNEMONICS.iter()
 			.map(|d| regex::escape(d)) // ensures special characters are treated literally
 			.collect::<Vec<String>>()
 			.join("|");
-		let dirs_pat = [DirBytesKeyword::WORD].iter()
+		let dirs_pat = [DirBytesKeyword::WORD, DirIncbinKeyword::WORD].iter()
 			.map(|d| regex::escape(d)) // ensures special characters are treated literally
 			.collect::<Vec<String>>()
 			.join("|");
@@ -244,50 +771,142 @@ impl Assemble for Raw
 		let re_dirs = Regex::new(&format!("^({})$", dirs_pat)).unwrap();
 
 		// First pass, record label addresses
-		loop
+		let mut cursor = Cursor::new(tokens);
+		while let Some(tok) = cursor.current()
 		{
-			let tok = if let Some(tok) = clean_peek.next()
+			*statement = cursor.pos.idx;
+			if at_file_start(sources, &cursor)
 			{
-				tok
+				current = 0;
 			}
-			else
+			if let Some(label) = label_declaration(&mut cursor)
 			{
-				// done
-				break;
-			};
+				let at = (current, sections[current].size);
+				let file = file_of(sources, *statement);
+				declare_label(&mut labels, &mut label_indices, label, at, file)?;
+				continue;
+			}
+
+			if let Some((name, flags)) = parse_section_directive(&mut cursor)?
+			{
+				current = switch_section(&mut sections, name, flags)?;
+				continue;
+			}
 
-			if tok.ends_with(':') || clean_peek.peek() == Some(&":")
+			if let Some((directive, symbols)) = parse_symbol_directive(&mut cursor)?
 			{
-				// Found the label
+				for sym in symbols
+				{
+					if directive == ".extern"
+					{
+						if !externs.contains(&sym)
+						{
+							externs.push(sym);
+						}
+						continue;
+					}
+					let visibility = if directive == ".global"
+					{
+						Visibility::Global
+					}
+					else
+					{
+						Visibility::Local
+					};
+					if visibilities
+						.insert(sym, visibility)
+						.is_some_and(|v| v != visibility)
+					{
+						return Err(format!("Symbol '{}' declared both global and local", sym));
+					}
+				}
+				continue;
+			}
 
-				let label = tok.split(':').next().unwrap();
-				if let Some(_) = label_addresses.insert(label, byte_count)
+			if let Some(metadata) = parse_metadata_directive(&mut cursor)?
+			{
+				match metadata
 				{
-					let mut msg = "'".to_string();
-					msg.push_str(label);
-					msg.push_str("' defined twice");
-					return Err(msg);
+					Metadata::Type(sym, kind) =>
+					{
+						kinds.insert(sym, kind);
+					},
+					Metadata::Size(sym, expr) =>
+					{
+						sizes.push((*statement, sym, expr, (current, sections[current].size)));
+					},
+					Metadata::Func(sym) =>
+					{
+						if function.is_some()
+						{
+							return Err("'.func' inside '.func'".to_owned());
+						}
+						let outer = current;
+						if self.function_sections
+						{
+							let name = function_section(&sections[current].name, sym);
+							current = switch_section(&mut sections, &name, None)?;
+						}
+						let at = (current, sections[current].size);
+						let file = file_of(sources, *statement);
+						declare_label(&mut labels, &mut label_indices, sym, at, file)?;
+						kinds.insert(sym, SymbolKind::Function);
+						function = Some((sym, current, outer));
+					},
+					Metadata::EndFunc =>
+					{
+						let (sym, section, outer) = function
+							.take()
+							.ok_or("'.endfunc' without '.func'".to_owned())?;
+						if section != current
+						{
+							return Err(format!(
+								"'.endfunc' of '{}' in different section than its '.func'",
+								sym
+							));
+						}
+						let label = &mut labels[label_indices[sym]];
+						label.size = Some(sections[current].size - label.offset);
+						current = outer;
+					},
 				}
 				continue;
 			}
 
+			if parse_endian_directive(&mut cursor)?.is_some()
+			{
+				continue;
+			}
+
+			if let Some((checksum, ..)) = parse_checksum_directive(&mut cursor)?
+			{
+				sections[current].size += checksum.size();
+				continue;
+			}
+
+			// Assertions are only checked once all labels are known
+			match parse_diagnostic(&mut cursor)?
+			{
+				Some(Diagnostic::Error(msg)) => return Err(format!("Error: {}", msg)),
+				Some(Diagnostic::Warning(msg)) =>
+				{
+					warnings.push((*statement, format!("Warning: {}", msg)));
+					continue;
+				},
+				Some(Diagnostic::Assert(..)) => continue,
+				None => (),
+			}
+
 			if re_dirs.is_match(tok)
 			{
 				// parse directive
-
-				match parse_bytes_direcive(
-					Some(tok).into_iter().chain(clean_peek.clone()),
-					|_: Resolve| Ok(2),
-				)
+				let endianness = Endianness::Little;
+				let (f, resolve) = (|_: Resolve| Ok(2), |_: Resolve| Ok(2));
+				match self.assemble_directive(&mut cursor, f, resolve, &mut files, endianness)
 				{
-					Ok((bytes, consumed)) =>
+					Ok(bytes) =>
 					{
-						byte_count += bytes.len() as i32;
-						consumed
-							.advance_iter_in_place(
-								&mut Some(tok).into_iter().chain(&mut clean_peek),
-							)
-							.1;
+						sections[current].size += bytes.len();
 						continue;
 					},
 					Err(err) =>
@@ -301,103 +920,679 @@ impl Assemble for Raw
 			else if re_mnems.is_match(tok)
 			{
 				// Start of instruction, count up 2 bytes
-				byte_count += 2;
+				sections[current].size += 2;
+			}
+			cursor.next_token();
+		}
+
+		if let Some((sym, ..)) = function
+		{
+			return Err(format!("'.func' of '{}' without '.endfunc'", sym));
+		}
+		for (sym, kind) in kinds
+		{
+			let label = label_indices
+				.get(sym)
+				.ok_or(format!("Symbol '{}' given a type but not defined", sym))?;
+			labels[*label].kind = kind;
+		}
+		for (sym, visibility) in visibilities
+		{
+			let label = label_indices.get(sym).ok_or(format!(
+				"Symbol '{}' declared {} but not defined",
+				sym,
+				match visibility
+				{
+					Visibility::Global => "global",
+					Visibility::Local => "local",
+				}
+			))?;
+			labels[*label].visibility = visibility;
+		}
+		// Externs that are defined here are treated as normal labels
+		externs.retain(|sym| !label_indices.contains_key(sym));
+
+		// Lay out the sections to get the label addresses
+		let origin = self.origin;
+		let bases = sections
+			.iter()
+			.zip(section_offsets(&sections))
+			.map(|(section, offset)| {
+				let base = u64::try_from(offset)
+					.ok()
+					.and_then(|offset| origin.checked_add(offset));
+				// As the section must end within range, so must any address in it
+				base.filter(|base| {
+					u64::try_from(section.size)
+						.ok()
+						.and_then(|size| base.checked_add(size))
+						.is_some()
+				})
+				.ok_or(format!("Origin out of range: {}", origin))
+			})
+			.collect::<Result<Vec<u64>, String>>()?;
+		let label_addresses: HashMap<&str, u64> = label_indices
+			.iter()
+			.map(|(name, idx)| {
+				let label = &labels[*idx];
+				(*name, bases[label.section] + label.offset as u64)
+			})
+			.collect();
+		// Gives the value of a symbol in an expression, where '.' is the given
+		// current address
+		let label_value = |here: u64, sym: &str| {
+			if sym == "."
+			{
+				return Ok(here as i128);
+			}
+			label_addresses
+				.get(sym)
+				.map(|addr| *addr as i128)
+				.or_else(|| self.defines.get(sym).cloned())
+				.ok_or(format!("Unknown label: {}", sym))
+		};
+		// Expressions in directives may contain extracted expressions, e.g. '.'
+		let symbol_value = |here: u64, sym: &str| {
+			match expression_index(sym).and_then(|idx| expressions.get(idx))
+			{
+				Some(expr) => expr.evaluate(&|sym| label_value(here, sym)),
+				None => label_value(here, sym),
 			}
+		};
+
+		for (size_statement, sym, expr, (section, offset)) in sizes
+		{
+			*statement = size_statement;
+			let label = label_indices
+				.get(sym)
+				.ok_or(format!("Symbol '{}' given a size but not defined", sym))?;
+			let here = bases[section] + offset as u64;
+			let size = expr.evaluate(&|sym| symbol_value(here, sym))?;
+			labels[*label].size =
+				Some(usize::try_from(size).map_err(|_| format!("Invalid size: {}", expr))?);
 		}
 
 		// Second pass, final assembly
-		let groups = GroupIter::<_, false> {
-			iter: cleaned.clone().peekable(),
+		let mut checksums = Vec::new();
+		// For each value split using bit extractions, the bits that were
+		// extracted and the first statement extracting them
+		let splits: RefCell<HashMap<(String, i128), (u128, usize)>> = RefCell::new(HashMap::new());
+		let mut endianness = Endianness::Little;
+		let mut offsets = vec![0; sections.len()];
+		let mut current = 0;
+		let mut relocations = Vec::new();
+		// The labels declared so far, to tell references to numeric labels apart
+		let mut declared = HashSet::new();
+		// The section the current function was declared in
+		let mut function_outer = None;
+		// Undefined symbols that weren't declared '.extern'
+		let mut implicit_imports: Vec<String> = Vec::new();
+		let is_import = |sym: &str| {
+			externs.contains(&sym)
+				|| (self.relocatable
+					&& !label_addresses.contains_key(sym)
+					&& expression_index(sym).is_none())
+		};
+		// The section and offset of the label with the given name
+		let label_location = |sym: &str| {
+			label_indices
+				.get(sym)
+				.map(|idx| (labels[*idx].section, labels[*idx].offset))
 		};
-		let mut result = Vec::with_capacity(byte_count as usize);
-		let mut byte_count = 0;
-		for mut group in groups
+		// The given text as written in the source, for recording relocations
+		let restore = |text: &str, declared: &HashSet<&str>| {
+			restore_references(&restore_expressions(text, expressions), names, |sym| {
+				declared.contains(sym)
+			})
+		};
+		// Whether the value of the reference, made from the given section, may
+		// change when linking.
+		// When relocatable, sections may be placed anywhere, so this includes
+		// the addresses of labels and distances between or to other sections,
+		// which also lets the linker see what each section uses.
+		let needs_relocation = |reference: &Reference, section: usize| {
+			let symbols = reference.symbols();
+			if symbols.iter().any(|sym| is_import(sym))
+			{
+				return true;
+			}
+			let sections: Vec<_> = symbols
+				.iter()
+				.filter_map(|sym| label_location(sym))
+				.map(|(section, _)| section)
+				.collect();
+			self.relocatable
+				&& match reference
+				{
+					Reference::Address(_) => !sections.is_empty(),
+					Reference::DistanceCurrent(_) => sections.iter().any(|s| *s != section),
+					Reference::Distance(..) =>
+					{
+						sections.len() == 2 && sections.iter().any(|s| *s != section)
+					},
+				}
+		};
+		let mut cursor = Cursor::new(tokens);
+		while let Some(tok) = cursor.current()
 		{
-			let mut next_token = None;
+			*statement = cursor.pos.idx;
+			if at_file_start(sources, &cursor)
+			{
+				current = 0;
+				endianness = Endianness::Little;
+			}
+			if let Some(label) = label_declaration(&mut cursor)
+			{
+				declared.insert(label);
+				continue;
+			}
+
+			if let Some((name, _)) = parse_section_directive(&mut cursor)?
+			{
+				current = sections.iter().position(|s| s.name == name).unwrap();
+				continue;
+			}
+
+			if parse_symbol_directive(&mut cursor)?.is_some()
+			{
+				continue;
+			}
 
-			loop
+			if let Some(metadata) = parse_metadata_directive(&mut cursor)?
 			{
-				let f = |resolve| {
-					match resolve
+				if let Metadata::Func(sym) = metadata
+				{
+					declared.insert(sym);
+				}
+				if self.function_sections
+				{
+					match metadata
 					{
-						Resolve::Address(sym) => label_addresses.get(sym).cloned().ok_or(sym),
-						Resolve::DistanceCurrent(sym) =>
+						Metadata::Func(sym) =>
 						{
-							label_addresses
-								.get(sym)
-								.ok_or(sym)
-								.map(|addr| addr - byte_count)
-						},
-						Resolve::Distance(sym1, sym2) =>
-						{
-							if !label_addresses.contains_key(sym2)
-							{
-								Err(sym2)
-							}
-							else if !label_addresses.contains_key(sym1)
-							{
-								Err(sym1)
-							}
-							else
-							{
-								Ok(label_addresses[sym2] - label_addresses[sym1])
-							}
+							let name = function_section(&sections[current].name, sym);
+							function_outer = Some(current);
+							current = sections.iter().position(|s| s.name == name).unwrap();
 						},
+						Metadata::EndFunc => current = function_outer.take().unwrap_or(current),
+						_ => (),
 					}
-				};
+				}
+				continue;
+			}
 
-				// Try to parse a directive
-				let all_tokens = next_token.clone().into_iter().chain(group.clone());
-				if let Ok((bytes, consumed)) = parse_bytes_direcive(all_tokens.clone(), f)
+			if let Some(new_endianness) = parse_endian_directive(&mut cursor)?
+			{
+				endianness = new_endianness;
+				continue;
+			}
+
+			let byte_count = bases[current] + offsets[current] as u64;
+			let symbol_value = |sym: &str| symbol_value(byte_count, sym);
+
+			if let Some((checksum, start, end)) = parse_checksum_directive(&mut cursor)?
+			{
+				// The linker may patch the checksummed bytes or move the range
+				if self.relocatable
 				{
-					byte_count += bytes.len() as i32;
-					result.extend(bytes.into_iter());
-					next_token = consumed
-						.advance_iter_in_place(&mut next_token.into_iter().chain(&mut group))
-						.1;
-					continue;
+					return Err("Checksum in relocatable object".to_owned());
+				}
+				let section = &mut sections[current];
+				if section.flags.no_bits
+				{
+					return Err(format!(
+						"Checksum in section without contents: {}",
+						section.name
+					));
 				}
+				let start = start.evaluate(&symbol_value)?;
+				let end = end.evaluate(&symbol_value)?;
+				checksums.push((
+					*statement,
+					checksum,
+					start,
+					end,
+					current,
+					offsets[current],
+					endianness,
+				));
+				// Zeroed until calculated
+				section
+					.bytes
+					.resize(section.bytes.len() + checksum.size(), 0);
+				offsets[current] += checksum.size();
+				continue;
+			}
 
-				// Try to parse an instruction
-				match Instruction::parse(all_tokens.clone(), f)
+			if let Some(diagnostic) = parse_diagnostic(&mut cursor)?
+			{
+				if let Diagnostic::Assert(expr, message) = diagnostic
 				{
-					Ok((instr, consumed)) =>
+					let value = expr.evaluate(&symbol_value)?;
+					if value == 0
 					{
-						result.write_u16::<LittleEndian>(instr.encode()).unwrap();
-						byte_count += 2;
-						next_token = consumed
-							.advance_iter_in_place(&mut next_token.into_iter().chain(&mut group))
-							.1;
-						continue;
-					},
-					Err(err) =>
+						return Err(format!(
+							"Assertion failed: {}",
+							message.unwrap_or(expr.to_string())
+						));
+					}
+				}
+				continue;
+			}
+
+			let section = &mut sections[current];
+			// Errors from evaluating expressions, which can't be returned through 'f'
+			let expression_error = RefCell::new(None);
+			// The references resolved by this statement, with their values
+			let references = RefCell::new(Vec::new());
+			let offset = offsets[current];
+			// Records relocations for the resolved references to imports, where
+			// 'field' gives the kind of field each reference is in
+			let mut relocate = |field: &dyn Fn(&Reference) -> RelocationField, source: Vec<&str>| {
+				let mut unique: Vec<(Reference, i128)> = Vec::new();
+				for reference in references.take()
+				{
+					if !unique.contains(&reference)
 					{
-						match err.err_type
+						unique.push(reference);
+					}
+				}
+				let (relocated, resolved): (Vec<_>, Vec<_>) = unique
+					.into_iter()
+					.partition(|(reference, _)| needs_relocation(reference, current));
+				// Only instructions use these, which take 32-bit values
+				let resolved = resolved
+					.into_iter()
+					.map(|(reference, value)| {
+						i64::try_from(value)
+							.map(|value| (reference, value))
+							.map_err(|_| format!("Value out of range: {}", value))
+					})
+					.collect::<Result<Vec<_>, _>>()?;
+				for (reference, _) in relocated
+				{
+					let symbols = reference.symbols();
+					for sym in symbols.iter().filter(|sym| is_import(sym))
+					{
+						if !externs.contains(sym) && !implicit_imports.iter().any(|s| s == sym)
 						{
-							ParseErrorType::UnknownSymbol =>
-							{
-								return Err(format!(
-									"Unknown label: {}",
-									err.extract_from_iter(all_tokens)
-								))
-							},
-							ParseErrorType::OutOfBoundValue(val, min, max) =>
-							{
-								return Err(format!(
-									"Invalid Value (Should be {} - {}): {}\nSource: {}",
-									min,
-									max,
-									val,
-									err.extract_from_iter(all_tokens)
-								))
-							},
-							// Group finished
-							_ => break,
+							implicit_imports.push(sym.to_string());
+						}
+					}
+					let targets = symbols.iter().map(|sym| label_location(sym)).collect();
+					relocations.push(Relocation {
+						section: current,
+						offset,
+						field: field(&reference),
+						reference: reference.map_symbols(|sym| restore(sym, &declared)),
+						targets,
+						source: source.iter().map(|t| restore(t, &declared)).collect(),
+						resolved: resolved
+							.iter()
+							.map(|(reference, value)| {
+								(reference.map_symbols(|sym| restore(sym, &declared)), *value)
+							})
+							.collect(),
+					});
+				}
+				Ok::<_, String>(())
+			};
+			let split_statement = *statement;
+			let address = |sym| {
+				if let Some(expr) = expression_index(sym).and_then(|idx| expressions.get(idx))
+				{
+					// Only constants have the same value once linked
+					let placed = expr.symbols().into_iter().find(|sym| {
+						label_addresses.contains_key(sym) || !self.defines.contains_key(*sym)
+					});
+					if let Some(placed) = placed.filter(|_| self.relocatable)
+					{
+						expression_error.replace(Some(format!(
+							"Address of '{}' used in expression of relocatable object: {}",
+							placed, expr
+						)));
+						return Err(sym);
+					}
+					for (operand, lo, hi) in expr.bits()
+					{
+						if let Ok(value) = operand.evaluate(&symbol_value)
+						{
+							let mut splits = splits.borrow_mut();
+							let (mask, _) = splits
+								.entry((operand.to_string(), value))
+								.or_insert((0, split_statement));
+							*mask |= bit_mask(hi - lo + 1) << lo;
 						}
+					}
+					return expr.evaluate(&symbol_value).map_err(|err| {
+						expression_error.replace(Some(err));
+						sym
+					});
+				}
+				label_addresses
+					.get(sym)
+					.map(|addr| *addr as i128)
+					.or(is_import(sym).then_some(byte_count as i128 + 2))
+					.ok_or(sym)
+			};
+			// Expression values may be too far apart to subtract
+			let distance = |from: i128, to: i128, sym| {
+				to.checked_sub(from).ok_or_else(|| {
+					expression_error.replace(Some(format!("Overflow: {} - {}", to, from)));
+					sym
+				})
+			};
+			let resolve = |to_resolve: Resolve<'t>| {
+				let reference = Reference::from(&to_resolve);
+				let value = match to_resolve
+				{
+					Resolve::Address(sym) => address(sym),
+					Resolve::DistanceCurrent(sym) =>
+					{
+						address(sym).and_then(|addr| distance(byte_count as i128, addr, sym))
+					},
+					Resolve::Distance(sym1, sym2) =>
+					{
+						let addr2 = address(sym2)?;
+						distance(address(sym1)?, addr2, sym2)
 					},
+				}?;
+				references.borrow_mut().push((reference, value));
+				Ok(value)
+			};
+			// 'scry_isa' only takes 32-bit values
+			let f = |to_resolve: Resolve<'t>| {
+				let sym = resolved_symbol(&to_resolve);
+				resolve(to_resolve).and_then(|value| {
+					i32::try_from(value).map_err(|_| {
+						expression_error.replace(Some(format!("Value out of range: {}", value)));
+						sym
+					})
+				})
+			};
+
+			// Try to parse a directive
+			if re_dirs.is_match(tok)
+			{
+				let bytes = self
+					.assemble_directive(&mut cursor, f, resolve, &mut files, endianness)
+					.map_err(|err| {
+						format!(
+							"Directive parsing error: {}",
+							expression_error.take().unwrap_or(err)
+						)
+					})?;
+				let big_endian = endianness == Endianness::Big;
+				let field = RelocationField::Bytes {
+					size: bytes.len(),
+					big_endian,
+				};
+				relocate(&|_| field, Vec::new())?;
+				offsets[current] += bytes.len();
+				if !section.flags.no_bits
+				{
+					section.bytes.extend(bytes);
 				}
+				else if bytes.iter().any(|b| *b != 0)
+				{
+					return Err(format!(
+						"Non-zero data in section without contents: {}",
+						section.name
+					));
+				}
+				continue;
+			}
+
+			// Try to parse an instruction
+			let start = cursor.pos;
+			let all_tokens = cursor.iter();
+			match Instruction::parse(all_tokens.clone(), f)
+			{
+				Ok((instr, consumed)) =>
+				{
+					if section.flags.no_bits
+					{
+						return Err(format!(
+							"Instruction in section without contents: {}",
+							section.name
+						));
+					}
+					section
+						.bytes
+						.write_u16::<LittleEndian>(instr.encode())
+						.unwrap();
+					offsets[current] += 2;
+					cursor.advance(consumed);
+					// Parses the instruction again, with the given reference's
+					// value moved by the given number of bytes
+					let resolved = references.borrow().clone();
+					let reparse = |moved: &Reference, delta: i128| {
+						let resolve = |to_resolve: Resolve<'t>| {
+							let reference = Reference::from(&to_resolve);
+							let delta = if reference == *moved { delta } else { 0 };
+							resolved
+								.iter()
+								.find(|(resolved, _)| *resolved == reference)
+								.and_then(|(_, value)| i32::try_from(value + delta).ok())
+								.ok_or(resolved_symbol(&to_resolve))
+						};
+						Instruction::parse(all_tokens.clone(), resolve)
+							.ok()
+							.map(|(instr, _)| instr)
+					};
+					let field = |reference: &Reference| {
+						instruction_field(&instr, |delta| reparse(reference, delta))
+					};
+					relocate(&field, cursor.consumed_since(start))?;
+				},
+				Err(err) =>
+				{
+					match err.err_type
+					{
+						ParseErrorType::UnknownSymbol =>
+						{
+							return Err(expression_error.take().unwrap_or(format!(
+								"Unknown label: {}",
+								restore_expressions(
+									&err.extract_from_iter(all_tokens),
+									expressions
+								)
+							)))
+						},
+						ParseErrorType::OutOfBoundValue(val, min, max) =>
+						{
+							return Err(format!(
+								"Invalid Value (Should be {} - {}): {}\nSource: {}",
+								min,
+								max,
+								val,
+								restore_expressions(
+									&err.extract_from_iter(all_tokens),
+									expressions
+								)
+							))
+						},
+						// Skip the rest of the group, until the next label
+						_ =>
+						{
+							while !cursor.is_done()
+								&& label_declaration(&mut cursor.clone()).is_none()
+							{
+								cursor.next_token();
+							}
+						},
+					}
+				},
 			}
 		}
-		Ok(result)
+		// Values that are split must be reconstructable from their parts
+		if let Some(((operand, value), (_, split_statement))) = splits
+			.into_inner()
+			.into_iter()
+			.filter(|((_, value), (mask, _))| !is_covered(*value, *mask))
+			.min_by_key(|(_, (_, split_statement))| *split_statement)
+		{
+			*statement = split_statement;
+			return Err(format!(
+				"Parts of '{}' don't reconstruct its value: {}",
+				operand, value
+			));
+		}
+
+		let mut object = Object {
+			sections,
+			labels,
+			imports: externs
+				.into_iter()
+				.map(str::to_owned)
+				.chain(implicit_imports)
+				.collect(),
+			relocations,
+			warnings: Vec::new(),
+			relocatable: self.relocatable,
+		};
+
+		// Checksums are calculated once all other bytes are final, with those
+		// covered by other checksums first
+		let mut image = object.bytes();
+		let mut pending = checksums;
+		while !pending.is_empty()
+		{
+			// The next checksum not covering the bytes of another pending one
+			let next = pending
+				.iter()
+				.position(|(_, _, start, end, own_section, own_offset, _)| {
+					pending.iter().all(|(_, other, _, _, section, offset, _)| {
+						let address = bases[*section] as i128 + *offset as i128;
+						(section, offset) == (own_section, own_offset)
+							|| address >= *end || address + other.size() as i128 <= *start
+					})
+				});
+			let Some(next) = next
+			else
+			{
+				*statement = pending[0].0;
+				return Err("Checksums cover each other".to_owned());
+			};
+			let (checksum_statement, checksum, start, end, section, offset, endianness) =
+				pending.remove(next);
+			*statement = checksum_statement;
+			let bytes = usize::try_from(start - origin as i128)
+				.ok()
+				.zip(usize::try_from(end - origin as i128).ok())
+				.and_then(|(start, end)| image.get(start..end))
+				.ok_or(format!(
+					"Checksum range out of bounds (start, end, size): {}, {}, {}",
+					start,
+					end,
+					image.len()
+				))?;
+			let mut value = checksum.calculate(bytes);
+			if endianness == Endianness::Big
+			{
+				value.reverse();
+			}
+			let address = (bases[section] - origin) as usize + offset;
+			image[address..address + value.len()].copy_from_slice(&value);
+			object.sections[section].bytes[offset..offset + value.len()].copy_from_slice(&value);
+		}
+		Ok(object)
+	}
+
+	/// Assembles the directive at the cursor.
+	///
+	/// Returns the bytes the directive produces.
+	/// Data values are emitted with the given endianness.
+	/// `f` is given to `scry_isa` parsers, while `resolve` gives the full
+	/// addresses of labels used in data.
+	fn assemble_directive<'t, F, G>(
+		&self,
+		cursor: &mut Cursor<'t>,
+		f: F,
+		resolve: G,
+		files: &mut HashMap<String, Vec<u8>>,
+		endianness: Endianness,
+	) -> Result<Vec<u8>, String>
+	where
+		F: Fn(Resolve<'t>) -> Result<i32, &'t str>,
+		G: Fn(Resolve<'t>) -> Result<i128, &'t str>,
+	{
+		match cursor.current().unwrap()
+		{
+			DirBytesKeyword::WORD =>
+			{
+				let (mut bytes, consumed) =
+					parse_bytes_direcive::<F, _>(cursor.iter(), &f, resolve)?;
+				cursor.advance(consumed);
+				if endianness == Endianness::Big
+				{
+					bytes.reverse();
+				}
+				Ok(bytes)
+			},
+			DirIncbinKeyword::WORD =>
+			{
+				cursor.next_token();
+				let (file, offset, length) = parse_incbin_directive(cursor)?;
+				if !files.contains_key(&file)
+				{
+					let contents = self
+						.loader
+						.load(&file)
+						.map_err(|err| format!("Failed to load '{}': {}", file, err))?;
+					files.insert(file.clone(), contents);
+				}
+				let contents = &files[&file];
+				let length = length.unwrap_or((contents.len() as u64).saturating_sub(offset));
+				// The range may not fit in the address space of the host
+				usize::try_from(offset)
+					.ok()
+					.zip(usize::try_from(length).ok())
+					.and_then(|(offset, length)| contents.get(offset..offset.checked_add(length)?))
+					.map(<[u8]>::to_vec)
+					.ok_or(format!(
+						"'{}' range out of bounds (offset, length, file size): {}, {}, {}",
+						file,
+						offset,
+						length,
+						contents.len()
+					))
+			},
+			_ => unreachable!(),
+		}
+	}
+}
+
+/// If a label is declared at the cursor, consumes the declaration and returns
+/// the label.
+fn label_declaration<'t>(cursor: &mut Cursor<'t>) -> Option<&'t str>
+{
+	let tok = cursor.current()?;
+	if let Some(label) = tok.strip_suffix(':')
+	{
+		cursor.next_token();
+		Some(label)
+	}
+	else if cursor.peek_next() == Some(":")
+	{
+		cursor.next_token();
+		cursor.next_token();
+		Some(tok)
+	}
+	else
+	{
+		None
+	}
+}
+
+impl Assemble for Raw
+{
+	type Error = String;
+
+	fn assemble<'a, I>(asm: I) -> Result<Vec<u8>, Self::Error>
+	where
+		I: Iterator<Item = &'a str> + Clone,
+	{
+		Raw::default().assemble_with(asm)
 	}
 }
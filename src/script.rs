@@ -0,0 +1,416 @@
+use crate::{link::LinkInput, object::Section};
+
+/// Describes where the linker places sections, using a subset of the syntax
+/// of `ld` linker scripts:
+///
+/// ```text
+/// /* Memory regions, with their start address and size */
+/// MEMORY
+/// {
+///     rom : ORIGIN = 0x0000, LENGTH = 4K
+///     ram : ORIGIN = 0x8000, LENGTH = 0x800
+/// }
+/// SECTIONS
+/// {
+///     /* Places '.text' (and '.text.*') of all objects in 'rom' */
+///     .text : > rom
+///     .rodata ALIGN(4) : > rom
+///     __data_start = ORIGIN(ram);
+///     .data : > ram
+///     __data_end = .;
+///     . = ALIGN(2);
+///     __stack_limit = .;
+///     __stack = 0x8800;
+/// }
+/// ```
+///
+/// Sections are placed in the order of the rules, one after the other in their
+/// region, with the sections of each object in the order the objects are
+/// given.
+/// `.` is the next free address of the region that was placed in last, or the
+/// first region if nothing was placed yet.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Script
+{
+	pub regions: Vec<Region>,
+	pub rules: Vec<Rule>,
+}
+
+/// A window of memory sections can be placed in.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Region
+{
+	pub name: String,
+	/// The address of the first byte of the region.
+	pub origin: u64,
+	/// The size of the region in bytes.
+	pub length: u64,
+}
+
+/// A statement in the `SECTIONS` of a [`Script`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Rule
+{
+	/// Places all sections with the given name, or subsections of it, in the
+	/// given region.
+	///
+	/// Each of them is aligned to the given number of bytes, and executable
+	/// ones to at least 2 bytes, the size of an instruction.
+	Place
+	{
+		section: String,
+		region: String,
+		align: u64,
+	},
+	/// Aligns `.` to the given number of bytes.
+	Align(u64),
+	/// Defines a global symbol.
+	Symbol(String, SymbolValue),
+}
+
+/// The address given to a symbol defined by a [`Script`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum SymbolValue
+{
+	/// `.`
+	Location,
+	/// `ORIGIN(region)`, the start of the given region.
+	Origin(String),
+	Address(u64),
+}
+
+/// The result of placing sections according to a [`Script`].
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub(crate) struct Layout
+{
+	/// The address of each section of each object.
+	pub addresses: Vec<Vec<u64>>,
+	/// The symbols defined by the script, with their addresses.
+	pub symbols: Vec<(String, u64)>,
+	/// Each region with the number of bytes used.
+	pub usage: Vec<(Region, u64)>,
+}
+
+impl Script
+{
+	/// Parses a linker script.
+	pub fn parse(script: &str) -> Result<Self, String>
+	{
+		let tokens = script_tokens(script)?;
+		let mut tokens = tokens.iter().map(String::as_str).peekable();
+		let mut result = Script::default();
+		while let Some(block) = tokens.next()
+		{
+			expect(&mut tokens, "{")?;
+			match block
+			{
+				"MEMORY" =>
+				{
+					while tokens.peek() != Some(&"}")
+					{
+						let name = word(&mut tokens)?;
+						expect(&mut tokens, ":")?;
+						expect(&mut tokens, "ORIGIN")?;
+						expect(&mut tokens, "=")?;
+						let origin = number(&mut tokens)?;
+						expect(&mut tokens, ",")?;
+						expect(&mut tokens, "LENGTH")?;
+						expect(&mut tokens, "=")?;
+						let length = number(&mut tokens)?;
+						result.regions.push(Region {
+							name: name.to_owned(),
+							origin,
+							length,
+						});
+					}
+				},
+				"SECTIONS" =>
+				{
+					while tokens.peek() != Some(&"}")
+					{
+						result.rules.push(parse_rule(&mut tokens)?);
+						if tokens.peek() == Some(&";")
+						{
+							tokens.next();
+						}
+					}
+				},
+				_ => return Err(format!("Unknown linker script command: {}", block)),
+			}
+			expect(&mut tokens, "}")?;
+		}
+		Ok(result)
+	}
+
+	/// Gives each section of the given objects an address.
+	pub(crate) fn place(&self, inputs: &[LinkInput]) -> Result<Layout, String>
+	{
+		let mut addresses: Vec<Vec<Option<u64>>> = inputs
+			.iter()
+			.map(|input| vec![None; input.object.sections.len()])
+			.collect();
+		let mut symbols = Vec::new();
+		// The next free address of each region
+		let mut next: Vec<u64> = self.regions.iter().map(|region| region.origin).collect();
+		let mut current = 0;
+		let overflow = |region: usize| {
+			format!(
+				"Region '{}' overflows the address space",
+				self.regions[region].name
+			)
+		};
+		for rule in &self.rules
+		{
+			match rule
+			{
+				Rule::Place {
+					section,
+					region,
+					align,
+				} =>
+				{
+					current = self
+						.regions
+						.iter()
+						.position(|r| r.name == *region)
+						.ok_or(format!("Unknown memory region: {}", region))?;
+					for (idx, input) in inputs.iter().enumerate()
+					{
+						for (section_idx, placed) in input.object.sections.iter().enumerate()
+						{
+							if addresses[idx][section_idx].is_none() && matches(placed, section)
+							{
+								let min_align = if placed.flags.execute { 2 } else { 1 };
+								let start = align_up(next[current], (*align).max(min_align))
+									.ok_or(overflow(current))?;
+								addresses[idx][section_idx] = Some(start);
+								next[current] = start
+									.checked_add(placed.size as u64)
+									.ok_or(overflow(current))?;
+							}
+						}
+					}
+				},
+				Rule::Align(align) =>
+				{
+					let next = next
+						.get_mut(current)
+						.ok_or(format!("'.' used without memory regions: ALIGN({})", align))?;
+					*next = align_up(*next, *align).ok_or(overflow(current))?;
+				},
+				Rule::Symbol(name, value) =>
+				{
+					let value = match value
+					{
+						SymbolValue::Location => next.get(current).copied(),
+						SymbolValue::Origin(region) =>
+						{
+							let region = self
+								.regions
+								.iter()
+								.find(|r| r.name == *region)
+								.ok_or(format!("Unknown memory region: {}", region))?;
+							Some(region.origin)
+						},
+						SymbolValue::Address(addr) => Some(*addr),
+					}
+					.ok_or(format!("'.' used without memory regions: {}", name))?;
+					symbols.push((name.clone(), value));
+				},
+			}
+		}
+
+		let mut usage = Vec::new();
+		for (region, next) in self.regions.iter().zip(next)
+		{
+			let used = next - region.origin;
+			if used > region.length
+			{
+				return Err(format!(
+					"Region '{}' overflows by {} bytes",
+					region.name,
+					used - region.length
+				));
+			}
+			usage.push((region.clone(), used));
+		}
+
+		let mut result = Vec::new();
+		for (input, addresses) in inputs.iter().zip(addresses)
+		{
+			let mut placed = Vec::new();
+			for (section, address) in input.object.sections.iter().zip(addresses)
+			{
+				// Empty sections don't need space anywhere
+				if address.is_none() && section.size > 0
+				{
+					return Err(format!(
+						"Section '{}' of '{}' not placed by the linker script",
+						section.name, input.name
+					));
+				}
+				placed.push(address.unwrap_or(0));
+			}
+			result.push(placed);
+		}
+		Ok(Layout {
+			addresses: result,
+			symbols,
+			usage,
+		})
+	}
+}
+
+/// Whether the section is placed by a rule for sections with the given name.
+fn matches(section: &Section, name: &str) -> bool
+{
+	section.name == name || section.name.starts_with(&format!("{}.", name))
+}
+
+/// Rounds the address up to a multiple of the alignment, if it fits.
+fn align_up(address: u64, align: u64) -> Option<u64>
+{
+	address.checked_next_multiple_of(align.max(1))
+}
+
+/// Parses a rule in `SECTIONS`.
+fn parse_rule<'a, I>(tokens: &mut std::iter::Peekable<I>) -> Result<Rule, String>
+where
+	I: Iterator<Item = &'a str>,
+{
+	let name = word(tokens)?;
+	if name == "."
+	{
+		expect(tokens, "=")?;
+		return Ok(Rule::Align(parse_align(tokens)?));
+	}
+	if tokens.peek() == Some(&"=")
+	{
+		tokens.next();
+		let value = match tokens.peek()
+		{
+			Some(&".") =>
+			{
+				tokens.next();
+				SymbolValue::Location
+			},
+			Some(&"ORIGIN") =>
+			{
+				tokens.next();
+				expect(tokens, "(")?;
+				let region = word(tokens)?;
+				expect(tokens, ")")?;
+				SymbolValue::Origin(region.to_owned())
+			},
+			_ => SymbolValue::Address(number(tokens)?),
+		};
+		return Ok(Rule::Symbol(name.to_owned(), value));
+	}
+	let align = if tokens.peek() == Some(&"ALIGN")
+	{
+		parse_align(tokens)?
+	}
+	else
+	{
+		1
+	};
+	expect(tokens, ":")?;
+	expect(tokens, ">")?;
+	Ok(Rule::Place {
+		section: name.to_owned(),
+		region: word(tokens)?.to_owned(),
+		align,
+	})
+}
+
+/// Parses `ALIGN(n)`.
+fn parse_align<'a, I>(tokens: &mut std::iter::Peekable<I>) -> Result<u64, String>
+where
+	I: Iterator<Item = &'a str>,
+{
+	expect(tokens, "ALIGN")?;
+	expect(tokens, "(")?;
+	let align = number(tokens)?;
+	expect(tokens, ")")?;
+	Ok(align)
+}
+
+/// Consumes the given token.
+fn expect<'a>(tokens: &mut impl Iterator<Item = &'a str>, expected: &str) -> Result<(), String>
+{
+	match tokens.next()
+	{
+		Some(token) if token == expected => Ok(()),
+		token => Err(format!("Expected '{}': {}", expected, token.unwrap_or(""))),
+	}
+}
+
+/// Consumes a name, or `.`.
+fn word<'a>(tokens: &mut impl Iterator<Item = &'a str>) -> Result<&'a str, String>
+{
+	tokens
+		.next()
+		.filter(|token| {
+			token
+				.chars()
+				.all(|c| c.is_alphanumeric() || c == '_' || c == '.')
+		})
+		.ok_or("Expected name".to_owned())
+}
+
+/// Consumes a decimal or hexadecimal number, optionally followed by `K` or `M`
+/// to multiply it by 1024 or 1024*1024.
+fn number<'a>(tokens: &mut impl Iterator<Item = &'a str>) -> Result<u64, String>
+{
+	let token = tokens.next().unwrap_or("");
+	let (digits, multiplier) = match token.as_bytes().last()
+	{
+		Some(b'K') => (&token[..token.len() - 1], 1024),
+		Some(b'M') => (&token[..token.len() - 1], 1024 * 1024),
+		_ => (token, 1),
+	};
+	match digits.strip_prefix("0x").or(digits.strip_prefix("0X"))
+	{
+		Some(hex) => u64::from_str_radix(hex, 16),
+		None => digits.parse(),
+	}
+	.ok()
+	.and_then(|value| value.checked_mul(multiplier))
+	.ok_or(format!("Invalid number: {}", token))
+}
+
+/// Splits a linker script into tokens, removing comments.
+fn script_tokens(script: &str) -> Result<Vec<String>, String>
+{
+	let mut tokens = Vec::new();
+	let mut rest = script;
+	while let Some(c) = rest.chars().next()
+	{
+		if let Some(comment) = rest.strip_prefix("/*")
+		{
+			let end = comment
+				.find("*/")
+				.ok_or("Unterminated comment".to_owned())?;
+			rest = &comment[end + 2..];
+		}
+		else if c.is_whitespace()
+		{
+			rest = &rest[c.len_utf8()..];
+		}
+		else if "{}:=,;>()".contains(c)
+		{
+			tokens.push(c.to_string());
+			rest = &rest[1..];
+		}
+		else
+		{
+			let len = rest
+				.find(|c: char| c.is_whitespace() || "{}:=,;>()".contains(c))
+				.unwrap_or(rest.len())
+				.min(rest.find("/*").unwrap_or(rest.len()));
+			tokens.push(rest[..len].to_owned());
+			rest = &rest[len..];
+		}
+	}
+	Ok(tokens)
+}
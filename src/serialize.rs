@@ -0,0 +1,391 @@
+use crate::object::{
+	Label, Object, Reference, Relocation, RelocationField, Section, SectionFlags, SymbolKind,
+	Visibility,
+};
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+
+/// Identifies serialized objects, including the version of the format.
+const MAGIC: &[u8; 8] = b"SCRYOBJ2";
+
+impl Object
+{
+	/// Serializes the object, such that it can be stored and linked later.
+	///
+	/// The format starts with the 8 bytes `SCRYOBJ2` and a byte that is 1 if
+	/// the object is relocatable and 0 otherwise, followed by the sections,
+	/// labels, imports, relocations and warnings, in that order.
+	/// All numbers are little-endian, sizes and offsets are 64-bit and indices
+	/// and counts are 32-bit.
+	/// Lists and strings are prefixed by their length, while optional values
+	/// are prefixed by a byte that is 1 if the value is present and 0
+	/// otherwise.
+	///
+	/// Fails if an index or count doesn't fit in 32 bits.
+	pub fn to_bytes(&self) -> Result<Vec<u8>, String>
+	{
+		let mut out = Writer(MAGIC.to_vec());
+		out.0.push(self.relocatable as u8);
+		out.list(&self.sections, |out, section| {
+			out.string(&section.name)?;
+			let flags = &section.flags;
+			out.0
+				.push(flags.write as u8 | (flags.execute as u8) << 1 | (flags.no_bits as u8) << 2);
+			out.size(section.size);
+			out.list(&section.bytes, |out, byte| {
+				out.0.push(*byte);
+				Ok(())
+			})
+		})?;
+		out.list(&self.labels, |out, label| {
+			out.string(&label.name)?;
+			out.index(label.section)?;
+			out.size(label.offset);
+			out.0.push(label.visibility as u8);
+			out.0.push(label.kind as u8);
+			out.option(&label.size, |out, size| {
+				out.size(*size);
+				Ok(())
+			})?;
+			out.option(&label.file, |out, file| out.string(file))
+		})?;
+		out.list(&self.imports, |out, import| out.string(import))?;
+		out.list(&self.relocations, |out, relocation| {
+			out.index(relocation.section)?;
+			out.size(relocation.offset);
+			match relocation.field
+			{
+				RelocationField::OutputOffset => out.0.push(0),
+				RelocationField::JumpTarget => out.0.push(1),
+				RelocationField::JumpLocation => out.0.push(2),
+				RelocationField::ConstImmediate => out.0.push(3),
+				RelocationField::Bytes { size, big_endian } =>
+				{
+					out.0.push(4);
+					out.size(size);
+					out.0.push(big_endian as u8);
+				},
+			}
+			out.reference(&relocation.reference)?;
+			out.list(&relocation.targets, |out, target| {
+				out.option(target, |out, (section, offset)| {
+					out.index(*section)?;
+					out.size(*offset);
+					Ok(())
+				})
+			})?;
+			out.list(&relocation.source, |out, token| out.string(token))?;
+			out.list(&relocation.resolved, |out, (reference, value)| {
+				out.reference(reference)?;
+				out.0.write_i64::<LittleEndian>(*value).unwrap();
+				Ok(())
+			})
+		})?;
+		out.list(&self.warnings, |out, warning| out.string(warning))?;
+		Ok(out.0)
+	}
+
+	/// Deserializes an object serialized using [`Object::to_bytes`].
+	pub fn from_bytes(bytes: &[u8]) -> Result<Self, String>
+	{
+		let mut input = Reader(
+			bytes
+				.strip_prefix(MAGIC)
+				.ok_or("Not a serialized object".to_owned())?,
+		);
+		let relocatable = input.byte()? != 0;
+		let sections = input.list(|input| {
+			let name = input.string()?;
+			let flags = input.byte()?;
+			Ok(Section {
+				name,
+				flags: SectionFlags {
+					write: flags & 1 != 0,
+					execute: flags & 2 != 0,
+					no_bits: flags & 4 != 0,
+				},
+				size: input.size()?,
+				bytes: input.list(Reader::byte)?,
+			})
+		})?;
+		let labels = input.list(|input| {
+			Ok(Label {
+				name: input.string()?,
+				section: input.index()?,
+				offset: input.size()?,
+				visibility: match input.byte()?
+				{
+					0 => Visibility::Local,
+					1 => Visibility::Global,
+					v => return Err(format!("Invalid visibility: {}", v)),
+				},
+				kind: match input.byte()?
+				{
+					0 => SymbolKind::Unknown,
+					1 => SymbolKind::Function,
+					2 => SymbolKind::Object,
+					k => return Err(format!("Invalid symbol kind: {}", k)),
+				},
+				size: input.option(Reader::size)?,
+				file: input.option(Reader::string)?,
+			})
+		})?;
+		let imports = input.list(Reader::string)?;
+		let relocations = input.list(|input| {
+			Ok(Relocation {
+				section: input.index()?,
+				offset: input.size()?,
+				field: match input.byte()?
+				{
+					0 => RelocationField::OutputOffset,
+					1 => RelocationField::JumpTarget,
+					2 => RelocationField::JumpLocation,
+					3 => RelocationField::ConstImmediate,
+					4 =>
+					{
+						RelocationField::Bytes {
+							size: input.size()?,
+							big_endian: input.byte()? != 0,
+						}
+					},
+					f => return Err(format!("Invalid relocation field: {}", f)),
+				},
+				reference: input.reference()?,
+				targets: input
+					.list(|input| input.option(|input| Ok((input.index()?, input.size()?))))?,
+				source: input.list(Reader::string)?,
+				resolved: input.list(|input| {
+					let reference = input.reference()?;
+					Ok((reference, input.number(|r| r.read_i64::<LittleEndian>())?))
+				})?,
+			})
+		})?;
+		let warnings = input.list(Reader::string)?;
+		if !input.0.is_empty()
+		{
+			return Err("Unexpected data after object".to_owned());
+		}
+		let object = Object {
+			sections,
+			labels,
+			imports,
+			relocations,
+			warnings,
+			relocatable,
+		};
+		object.validate()?;
+		Ok(object)
+	}
+
+	/// Checks that the labels and relocations of a deserialized object are
+	/// within its sections, such that linking it can't panic.
+	fn validate(&self) -> Result<(), String>
+	{
+		let section = |idx: usize| {
+			self.sections
+				.get(idx)
+				.ok_or(format!("Invalid section index: {}", idx))
+		};
+		for section in &self.sections
+		{
+			let contents = if section.flags.no_bits
+			{
+				0
+			}
+			else
+			{
+				section.size
+			};
+			if section.bytes.len() != contents
+			{
+				return Err(format!("Invalid contents of section '{}'", section.name));
+			}
+		}
+		for label in &self.labels
+		{
+			if label.offset > section(label.section)?.size
+			{
+				return Err(format!("Label '{}' outside of its section", label.name));
+			}
+		}
+		for relocation in &self.relocations
+		{
+			let size = match relocation.field
+			{
+				RelocationField::Bytes { size, .. } if ![1, 2, 4, 8, 16].contains(&size) =>
+				{
+					return Err(format!("Invalid relocation size: {}", size));
+				},
+				RelocationField::Bytes { size, .. } => size,
+				_ => 2,
+			};
+			// Relocations patch the contents, so sections without any can't have them
+			let contents = section(relocation.section)?.bytes.len();
+			if relocation.offset > contents || contents - relocation.offset < size
+			{
+				return Err(format!(
+					"Relocation outside of its section at offset {}",
+					relocation.offset
+				));
+			}
+			if relocation.targets.len() != relocation.reference.symbols().len()
+			{
+				return Err("Invalid number of relocation targets".to_owned());
+			}
+			for (target, offset) in relocation.targets.iter().flatten()
+			{
+				if *offset > section(*target)?.size
+				{
+					return Err(format!(
+						"Relocation target outside of its section at offset {}",
+						offset
+					));
+				}
+			}
+		}
+		Ok(())
+	}
+}
+
+/// Writes the parts of a serialized object.
+struct Writer(Vec<u8>);
+impl Writer
+{
+	fn index(&mut self, index: usize) -> Result<(), String>
+	{
+		let index = u32::try_from(index)
+			.map_err(|_| format!("Index out of range for serialized object: {}", index))?;
+		self.0.write_u32::<LittleEndian>(index).unwrap();
+		Ok(())
+	}
+
+	fn size(&mut self, size: usize)
+	{
+		self.0.write_u64::<LittleEndian>(size as u64).unwrap();
+	}
+
+	fn string(&mut self, string: &str) -> Result<(), String>
+	{
+		self.index(string.len())?;
+		self.0.extend_from_slice(string.as_bytes());
+		Ok(())
+	}
+
+	fn list<T>(
+		&mut self,
+		items: &[T],
+		mut f: impl FnMut(&mut Self, &T) -> Result<(), String>,
+	) -> Result<(), String>
+	{
+		self.index(items.len())?;
+		for item in items
+		{
+			f(self, item)?;
+		}
+		Ok(())
+	}
+
+	fn option<T>(
+		&mut self,
+		item: &Option<T>,
+		f: impl FnOnce(&mut Self, &T) -> Result<(), String>,
+	) -> Result<(), String>
+	{
+		self.0.push(item.is_some() as u8);
+		if let Some(item) = item
+		{
+			f(self, item)?;
+		}
+		Ok(())
+	}
+
+	fn reference(&mut self, reference: &Reference) -> Result<(), String>
+	{
+		self.0.push(match reference
+		{
+			Reference::Address(_) => 0,
+			Reference::DistanceCurrent(_) => 1,
+			Reference::Distance(..) => 2,
+		});
+		for sym in reference.symbols()
+		{
+			self.string(sym)?;
+		}
+		Ok(())
+	}
+}
+
+/// Reads the parts of a serialized object.
+struct Reader<'a>(&'a [u8]);
+impl Reader<'_>
+{
+	fn number<T>(&mut self, f: impl FnOnce(&mut &[u8]) -> std::io::Result<T>) -> Result<T, String>
+	{
+		f(&mut self.0).map_err(|_| "Unexpected end of object".to_owned())
+	}
+
+	fn byte(&mut self) -> Result<u8, String>
+	{
+		self.number(|r| r.read_u8())
+	}
+
+	fn index(&mut self) -> Result<usize, String>
+	{
+		self.number(|r| r.read_u32::<LittleEndian>())
+			.map(|i| i as usize)
+	}
+
+	fn size(&mut self) -> Result<usize, String>
+	{
+		let size = self.number(|r| r.read_u64::<LittleEndian>())?;
+		usize::try_from(size).map_err(|_| format!("Size too large: {}", size))
+	}
+
+	fn string(&mut self) -> Result<String, String>
+	{
+		let len = self.index()?;
+		let bytes = self
+			.0
+			.get(..len)
+			.ok_or("Unexpected end of object".to_owned())?;
+		self.0 = &self.0[len..];
+		String::from_utf8(bytes.to_vec()).map_err(|_| "Invalid string in object".to_owned())
+	}
+
+	fn list<T>(
+		&mut self,
+		mut f: impl FnMut(&mut Self) -> Result<T, String>,
+	) -> Result<Vec<T>, String>
+	{
+		let len = self.index()?;
+		// Every item takes at least a byte, so this can't be bigger than the rest
+		let mut items = Vec::with_capacity(len.min(self.0.len()));
+		for _ in 0..len
+		{
+			items.push(f(self)?);
+		}
+		Ok(items)
+	}
+
+	fn option<T>(
+		&mut self,
+		f: impl FnOnce(&mut Self) -> Result<T, String>,
+	) -> Result<Option<T>, String>
+	{
+		match self.byte()?
+		{
+			0 => Ok(None),
+			_ => f(self).map(Some),
+		}
+	}
+
+	fn reference(&mut self) -> Result<Reference, String>
+	{
+		Ok(match self.byte()?
+		{
+			0 => Reference::Address(self.string()?),
+			1 => Reference::DistanceCurrent(self.string()?),
+			2 => Reference::Distance(self.string()?, self.string()?),
+			r => return Err(format!("Invalid reference: {}", r)),
+		})
+	}
+}
@@ -0,0 +1,796 @@
+use scry_asm::{
+	Archive, Discarded, Image, LinkInput, Linker, Object, Raw, RelocationField, Rule, Script,
+	Segment, SymbolValue,
+};
+use scry_isa::{AluVariant, Instruction};
+
+/// Assembles the given assembly for linking.
+fn object(asm: &[&str]) -> Object
+{
+	Raw {
+		relocatable: true,
+		..Raw::default()
+	}
+	.assemble_object(asm.iter().cloned())
+	.unwrap()
+}
+
+/// Links the given objects, named by their index, at the given addresses.
+fn link(objects: Vec<(Object, u64)>) -> Result<Image, String>
+{
+	Linker {
+		inputs: objects
+			.into_iter()
+			.enumerate()
+			.map(|(idx, (object, base))| {
+				LinkInput {
+					name: idx.to_string(),
+					object,
+					base,
+				}
+			})
+			.collect(),
+		..Linker::default()
+	}
+	.link()
+}
+
+#[test]
+fn link_objects()
+{
+	let main = object(&[
+		".global main",
+		"main:",
+		"nop",
+		"add =>print",
+		".bytes u16, main",
+	]);
+	let print = object(&[".global print", "print:", "nop", ".bytes u16, main"]);
+	let image = link(vec![(main, 0x100), (print, 0x110)]).unwrap();
+
+	let add = Instruction::Alu(AluVariant::Add, 6.try_into().unwrap())
+		.encode()
+		.to_le_bytes();
+	assert_eq!(
+		image.segments,
+		vec![
+			Segment {
+				address: 0x100,
+				bytes: vec![0, 0, add[0], add[1], 0x00, 0x01],
+			},
+			Segment {
+				address: 0x110,
+				bytes: vec![0, 0, 0x00, 0x01],
+			},
+		]
+	);
+	assert_eq!(image.symbols["main"], 0x100);
+	assert_eq!(image.symbols["print"], 0x110);
+}
+
+#[test]
+fn link_local_addresses()
+{
+	let data = object(&[
+		".data",
+		"value:",
+		".bytes u8, 7",
+		".text",
+		".bytes u16, value",
+	]);
+	let image = link(vec![(data, 0x40)]).unwrap();
+	// '.text' comes first, so 'value' is placed after it
+	assert_eq!(
+		image.segments,
+		vec![Segment {
+			address: 0x40,
+			bytes: vec![0x42, 0x00, 7],
+		}]
+	);
+}
+
+#[test]
+fn link_local_and_numeric_labels()
+{
+	let asm = [
+		"func:",
+		"add =>.value",
+		"add =>1f",
+		".data",
+		".value:",
+		".bytes u8, 7",
+		"1:",
+		".bytes u8, 8",
+	];
+	let image = link(vec![(object(&asm), 0)]).unwrap();
+	let expected = Raw::default().assemble_object(asm.into_iter()).unwrap();
+	assert_eq!(
+		image.segments,
+		vec![Segment {
+			address: 0,
+			bytes: expected.bytes(),
+		}]
+	);
+}
+
+#[test]
+fn link_undefined_symbol()
+{
+	assert_eq!(
+		link(vec![(object(&["nop", "add =>missing"]), 0)]),
+		Err("Undefined symbol 'missing' referenced in '0'".to_owned())
+	);
+}
+
+#[test]
+fn link_duplicate_definition()
+{
+	let main = || object(&[".global main", "main:", "nop"]);
+	assert_eq!(
+		link(vec![(main(), 0), (main(), 2)]),
+		Err("'main' defined in both '0' and '1'".to_owned())
+	);
+}
+
+#[test]
+fn link_data_out_of_range()
+{
+	let data = object(&[".bytes u8, print"]);
+	let print = object(&[".global print", "print:", "nop"]);
+	assert_eq!(
+		link(vec![(data, 0), (print, 0x200)]),
+		Err("Value out of range for 1-byte field in '0': 512".to_owned())
+	);
+}
+
+#[test]
+fn link_instruction_out_of_range()
+{
+	let main = object(&["add =>print"]);
+	let print = object(&[".global print", "print:", "nop"]);
+	let err = link(vec![(main, 0), (print, 0x1000)]).unwrap_err();
+	assert!(err.starts_with("Relocation out of range in '0'"), "{}", err);
+}
+
+#[test]
+fn link_overlapping_objects()
+{
+	let nop = || object(&["nop", "nop"]);
+	assert_eq!(
+		link(vec![(nop(), 0), (nop(), 4)]).map(|image| image.segments[0].bytes.len()),
+		Ok(8)
+	);
+	assert_eq!(
+		link(vec![(nop(), 0), (nop(), 2)]),
+		Err("Section '.text' of '0' overlaps section '.text' of '1'".to_owned())
+	);
+}
+
+#[test]
+fn link_aligns_instructions()
+{
+	let main = object(&[
+		".data",
+		".bytes u8, 1",
+		".section .text.start",
+		".global start",
+		"start:",
+		"nop",
+	]);
+	let image = link(vec![(main, 0x100)]).unwrap();
+	// '.text.start' follows the odd-sized '.data', so is aligned to instructions
+	assert_eq!(image.symbols["start"], 0x102);
+	assert_eq!(
+		image.segments,
+		vec![
+			Segment {
+				address: 0x100,
+				bytes: vec![1],
+			},
+			Segment {
+				address: 0x102,
+				bytes: vec![0, 0],
+			},
+		]
+	);
+}
+
+#[test]
+fn link_addresses_beyond_63_bits()
+{
+	let data = object(&[".bytes u64, print"]);
+	let print = object(&[".global print", "print:", "nop"]);
+	let image = link(vec![(data, 0), (print, 0xFFFF_FFFF_0000_0000)]).unwrap();
+	assert_eq!(image.segments[0].bytes, 0xFFFF_FFFF_0000_0000u64.to_le_bytes());
+	assert_eq!(image.symbols["print"], 0xFFFF_FFFF_0000_0000);
+}
+
+#[test]
+fn link_address_overflow()
+{
+	assert_eq!(
+		link(vec![(object(&["nop"]), u64::MAX - 1)]),
+		Err("Address out of range in '0'".to_owned())
+	);
+}
+
+/// Links the given objects, named by their index, using the given script.
+fn link_script(objects: Vec<Object>, script: &str) -> Result<Image, String>
+{
+	Linker {
+		inputs: objects
+			.into_iter()
+			.enumerate()
+			.map(|(idx, object)| {
+				LinkInput {
+					name: idx.to_string(),
+					object,
+					base: 0,
+				}
+			})
+			.collect(),
+		script: Some(Script::parse(script)?),
+		..Linker::default()
+	}
+	.link()
+}
+
+const SCRIPT: &str = "
+	/* ROM and RAM windows */
+	MEMORY
+	{
+		rom : ORIGIN = 0x100, LENGTH = 0x10
+		ram : ORIGIN = 0x8000, LENGTH = 1K
+	}
+	SECTIONS
+	{
+		.text : > rom
+		__data_start = ORIGIN(ram);
+		.data ALIGN(4) : > ram
+		__bss_start = .;
+		.bss : > ram
+		__bss_end = .;
+		__stack = 0x8400;
+	}
+";
+
+#[test]
+fn parse_linker_script()
+{
+	let script = Script::parse(SCRIPT).unwrap();
+	assert_eq!(script.regions[1].name, "ram");
+	assert_eq!(script.regions[1].length, 1024);
+	assert_eq!(
+		script.rules[2],
+		Rule::Place {
+			section: ".data".to_owned(),
+			region: "ram".to_owned(),
+			align: 4,
+		}
+	);
+	assert_eq!(
+		script.rules[1],
+		Rule::Symbol(
+			"__data_start".to_owned(),
+			SymbolValue::Origin("ram".to_owned())
+		)
+	);
+	assert_eq!(
+		script.rules[3],
+		Rule::Symbol("__bss_start".to_owned(), SymbolValue::Location)
+	);
+	assert_eq!(
+		script.rules[6],
+		Rule::Symbol("__stack".to_owned(), SymbolValue::Address(0x8400))
+	);
+}
+
+#[test]
+fn link_with_regions()
+{
+	let main = object(&[
+		".global main",
+		"main:",
+		"nop",
+		".bytes u16, __bss_start",
+		".data",
+		".bytes u8, 1",
+		".section .text.init",
+		"nop",
+	]);
+	let other = object(&[
+		".data",
+		"value:",
+		".bytes u8, 2",
+		".text",
+		".bytes u16, value",
+		".bss",
+		".bytes u16, 0",
+	]);
+	let image = link_script(vec![main, other], SCRIPT).unwrap();
+
+	// '.text.init' is placed with '.text', while '.bss' has no contents
+	assert_eq!(
+		image.segments,
+		vec![
+			Segment {
+				address: 0x100,
+				bytes: vec![0, 0, 0x05, 0x80, 0, 0, 0x04, 0x80],
+			},
+			Segment {
+				address: 0x8000,
+				bytes: vec![1],
+			},
+			// Each '.data' is aligned to 4 bytes
+			Segment {
+				address: 0x8004,
+				bytes: vec![2],
+			},
+		]
+	);
+	assert_eq!(image.symbols["main"], 0x100);
+	assert_eq!(image.symbols["__data_start"], 0x8000);
+	assert_eq!(image.symbols["__bss_start"], 0x8005);
+	assert_eq!(image.symbols["__bss_end"], 0x8007);
+	assert_eq!(image.symbols["__stack"], 0x8400);
+	assert!(image
+		.map
+		.contains("rom              0x00000100 0x00000010 used 0x8\n"));
+	assert!(image
+		.map
+		.contains("0x00000104 0x00000002 .text.init       0\n"));
+	assert!(image.map.contains("0x00008005 __bss_start\n"));
+}
+
+#[test]
+fn link_script_aligns_instructions()
+{
+	let odd = object(&[".bytes u8, 1"]);
+	let main = object(&[".global main", "main:", "nop"]);
+	let image = link_script(vec![odd, main], SCRIPT).unwrap();
+	// Executable sections are aligned to instructions, even without 'ALIGN'
+	assert_eq!(image.symbols["main"], 0x102);
+	assert_eq!(image.segments[0].bytes, [1]);
+	assert_eq!(image.segments[1].address, 0x102);
+}
+
+#[test]
+fn link_distant_regions()
+{
+	let script = "
+		MEMORY
+		{
+			rom : ORIGIN = 0x0, LENGTH = 64K
+			ram : ORIGIN = 0x80000000, LENGTH = 64K
+		}
+		SECTIONS
+		{
+			.text : > rom
+			.data : > ram
+			.bss : > ram
+		}
+	";
+	let main = object(&["nop", ".data", ".bytes u8, 1", ".bss", ".bytes u16, 0"]);
+	let image = link_script(vec![main], script).unwrap();
+	// Only the contents of sections are included, not the space between them
+	assert_eq!(
+		image.segments,
+		vec![
+			Segment {
+				address: 0,
+				bytes: vec![0, 0],
+			},
+			Segment {
+				address: 0x8000_0000,
+				bytes: vec![1],
+			},
+		]
+	);
+}
+
+#[test]
+fn link_region_overflow()
+{
+	let nops = object(&["nop"; 9]);
+	assert_eq!(
+		link_script(vec![nops], SCRIPT),
+		Err("Region 'rom' overflows by 2 bytes".to_owned())
+	);
+}
+
+#[test]
+fn link_section_not_placed()
+{
+	let rodata = object(&[".section .rodata", ".bytes u8, 1"]);
+	assert_eq!(
+		link_script(vec![rodata], SCRIPT),
+		Err("Section '.rodata' of '0' not placed by the linker script".to_owned())
+	);
+}
+
+#[test]
+fn link_script_symbol_defined_twice()
+{
+	let stack = object(&[".global __stack", "__stack:", "nop"]);
+	assert_eq!(
+		link_script(vec![stack], SCRIPT),
+		Err("'__stack' defined in both the linker script and '0'".to_owned())
+	);
+}
+
+#[test]
+fn linker_script_errors()
+{
+	assert_eq!(
+		Script::parse("SECTIONS { .text : > rom }\nMEMORY { rom : ORIGIN = 0, LENGTH = 1Q }"),
+		Err("Invalid number: 1Q".to_owned())
+	);
+	assert_eq!(
+		link_script(vec![object(&["nop"])], "SECTIONS { .text : > flash }"),
+		Err("Unknown memory region: flash".to_owned())
+	);
+	assert_eq!(
+		link_script(vec![object(&["nop"])], "SECTIONS { . = ALIGN(4); }"),
+		Err("'.' used without memory regions: ALIGN(4)".to_owned())
+	);
+	let top = "MEMORY { top : ORIGIN = 0xFFFFFFFFFFFFFFFF, LENGTH = 1 }";
+	assert_eq!(
+		link_script(
+			vec![object(&["nop"])],
+			&format!("{} SECTIONS {{ .text : > top }}", top)
+		),
+		Err("Region 'top' overflows the address space".to_owned())
+	);
+	assert_eq!(
+		link_script(
+			vec![object(&[".data", ".bytes u8, 1"])],
+			&format!("{} SECTIONS {{ .data ALIGN(0x8000000000000000) : > top }}", top)
+		),
+		Err("Region 'top' overflows the address space".to_owned())
+	);
+}
+
+#[test]
+fn discard_unreachable_sections()
+{
+	let object = Raw {
+		relocatable: true,
+		function_sections: true,
+		..Raw::default()
+	}
+	.assemble_object(
+		[
+			".global main",
+			".func main",
+			"add =>used",
+			".endfunc",
+			".func used",
+			"nop",
+			".endfunc",
+			".func unused",
+			"nop",
+			"nop",
+			".endfunc",
+			".data",
+			".bytes u8, 1",
+		]
+		.into_iter(),
+	)
+	.unwrap();
+	let image = Linker {
+		inputs: vec![LinkInput {
+			name: "main".to_owned(),
+			object,
+			base: 0,
+		}],
+		gc_entry: Some("main".to_owned()),
+		..Linker::default()
+	}
+	.link()
+	.unwrap();
+
+	let add = Instruction::Alu(AluVariant::Add, 0.try_into().unwrap())
+		.encode()
+		.to_le_bytes();
+	assert_eq!(image.segments[0].bytes, vec![add[0], add[1], 0, 0]);
+	assert_eq!(
+		image.discarded,
+		vec![
+			Discarded {
+				object: "main".to_owned(),
+				section: ".text.unused".to_owned(),
+				size: 4,
+			},
+			Discarded {
+				object: "main".to_owned(),
+				section: ".data".to_owned(),
+				size: 1,
+			},
+		]
+	);
+	assert!(image.map.contains("Discarded sections (5 bytes saved):\n"));
+}
+
+#[test]
+fn discard_needs_entry()
+{
+	let image = Linker {
+		inputs: vec![LinkInput {
+			name: "main".to_owned(),
+			object: object(&["nop"]),
+			base: 0,
+		}],
+		gc_entry: Some("start".to_owned()),
+		..Linker::default()
+	}
+	.link();
+	assert_eq!(image, Err("Undefined entry symbol: start".to_owned()));
+}
+
+#[test]
+fn discard_keeps_sections_of_distances()
+{
+	// Only the distance between the labels is used, which doesn't change
+	let object = object(&[
+		".global main",
+		"main:",
+		".bytes u8, first=>last",
+		".data",
+		"first:",
+		".bytes u8, 7",
+		"last:",
+	]);
+	let image = Linker {
+		inputs: vec![LinkInput {
+			name: "main".to_owned(),
+			object,
+			base: 0,
+		}],
+		gc_entry: Some("main".to_owned()),
+		..Linker::default()
+	}
+	.link()
+	.unwrap();
+	assert_eq!(image.segments[0].bytes, vec![1, 7]);
+	assert_eq!(image.discarded, vec![]);
+}
+
+#[test]
+fn discard_needs_relocatable_objects()
+{
+	let object = Raw::default()
+		.assemble_object([".global main", "main:", "nop"].into_iter())
+		.unwrap();
+	let image = Linker {
+		inputs: vec![LinkInput {
+			name: "main".to_owned(),
+			object,
+			base: 0,
+		}],
+		gc_entry: Some("main".to_owned()),
+		..Linker::default()
+	}
+	.link();
+	assert_eq!(
+		image,
+		Err("Can't discard sections of 'main', as it isn't relocatable".to_owned())
+	);
+}
+
+/// An archive of helper routines, where 'double' uses 'add_one'.
+fn helpers() -> Archive
+{
+	Archive::new(vec![
+		(
+			"add_one.o".to_owned(),
+			object(&[".global add_one", "add_one:", "nop"]),
+		),
+		(
+			"double.o".to_owned(),
+			object(&[".global double", "double:", "nop", ".bytes u16, add_one"]),
+		),
+		(
+			"a_very_long_member_name.o".to_owned(),
+			object(&[".global unused", "unused:", "nop"]),
+		),
+	])
+}
+
+#[test]
+fn archive_round_trip()
+{
+	let archive = helpers();
+	assert_eq!(archive.index["add_one"], 0);
+	assert_eq!(archive.index["double"], 1);
+	assert_eq!(archive.index["unused"], 2);
+
+	let bytes = archive.to_bytes().unwrap();
+	assert!(bytes.starts_with(b"!<arch>\n/ "));
+	assert_eq!(Archive::from_bytes(&bytes), Ok(archive));
+	assert_eq!(
+		Archive::from_bytes(b"SCRYOBJ1"),
+		Err("Not an archive".to_owned())
+	);
+}
+
+#[test]
+fn archive_non_ascii_member_name()
+{
+	let archive = Archive::new(vec![("é.o".to_owned(), object(&["nop"]))]);
+	let bytes = archive.to_bytes().unwrap();
+	// Non-ASCII names are in '//', keeping headers the same size
+	let long_name = "é.o/\n".as_bytes();
+	assert!(bytes.windows(long_name.len()).any(|w| w == long_name));
+	assert_eq!(Archive::from_bytes(&bytes), Ok(archive));
+}
+
+#[test]
+fn archive_index_to_wrong_member()
+{
+	let mut archive = helpers();
+	archive.index.insert("double".to_owned(), 0);
+	assert_eq!(
+		Archive::from_bytes(&archive.to_bytes().unwrap()),
+		Err("Invalid archive symbol index: 'add_one.o' doesn't define 'double'".to_owned())
+	);
+
+	let main = object(&[".global main", "main:", "nop", ".bytes u16, double"]);
+	let image = Linker {
+		inputs: vec![LinkInput {
+			name: "main".to_owned(),
+			object: main,
+			base: 0x100,
+		}],
+		archives: vec![("helpers.a".to_owned(), archive)],
+		..Linker::default()
+	}
+	.link();
+	assert_eq!(
+		image,
+		Err("Archive 'helpers.a' indexes 'double' to a member not defining it".to_owned())
+	);
+}
+
+#[test]
+fn object_round_trip()
+{
+	let main = object(&[
+		".global main",
+		"main:",
+		"add =>print",
+		"const u8, print",
+		"call print",
+		"ret print",
+		"jmp main, print",
+		".data",
+		".bytes u16, main",
+	]);
+	// Every kind of field is kept
+	let fields: Vec<_> = main.relocations.iter().map(|r| r.field).collect();
+	assert_eq!(
+		fields[..5],
+		[
+			RelocationField::OutputOffset,
+			RelocationField::ConstImmediate,
+			RelocationField::JumpTarget,
+			RelocationField::JumpLocation,
+			RelocationField::JumpLocation,
+		]
+	);
+	assert_eq!(Object::from_bytes(&main.to_bytes().unwrap()), Ok(main.clone()));
+	assert!(Object::from_bytes(&main.to_bytes().unwrap()[..20]).is_err());
+}
+
+#[test]
+fn object_index_out_of_range()
+{
+	let mut main = object(&["main:", "nop"]);
+	main.labels[0].section = u32::MAX as usize + 1;
+	assert_eq!(
+		main.to_bytes(),
+		Err("Index out of range for serialized object: 4294967296".to_owned())
+	);
+	assert_eq!(
+		Archive::new(vec![("main.o".to_owned(), main)]).to_bytes(),
+		Err(
+			"Index out of range for serialized object: 4294967296\nIn archive member 'main.o'"
+				.to_owned()
+		)
+	);
+}
+
+#[test]
+fn invalid_object()
+{
+	let main = object(&[
+		".global main",
+		"main:",
+		"add =>print",
+		".data",
+		".bytes u16, main",
+	]);
+	let invalid = |change: fn(&mut Object)| {
+		let mut object = main.clone();
+		change(&mut object);
+		Object::from_bytes(&object.to_bytes().unwrap())
+	};
+	assert_eq!(
+		invalid(|o| o.labels[0].section = 9),
+		Err("Invalid section index: 9".to_owned())
+	);
+	assert_eq!(
+		invalid(|o| o.labels[0].offset = 3),
+		Err("Label 'main' outside of its section".to_owned())
+	);
+	assert_eq!(
+		invalid(|o| o.relocations[0].offset = 1),
+		Err("Relocation outside of its section at offset 1".to_owned())
+	);
+	assert_eq!(
+		invalid(|o| {
+			o.relocations[1].field = RelocationField::Bytes {
+				size: 3,
+				big_endian: false,
+			}
+		}),
+		Err("Invalid relocation size: 3".to_owned())
+	);
+	assert_eq!(
+		invalid(|o| o.relocations[1].targets[0] = Some((0, 4))),
+		Err("Relocation target outside of its section at offset 4".to_owned())
+	);
+	assert_eq!(
+		invalid(|o| {
+			o.sections[0].bytes.pop();
+		}),
+		Err("Invalid contents of section '.text'".to_owned())
+	);
+}
+
+#[test]
+fn link_archive_members()
+{
+	let main = object(&[".global main", "main:", "nop", ".bytes u16, double"]);
+	let image = Linker {
+		inputs: vec![LinkInput {
+			name: "main".to_owned(),
+			object: main,
+			base: 0x100,
+		}],
+		archives: vec![("helpers.a".to_owned(), helpers())],
+		..Linker::default()
+	}
+	.link()
+	.unwrap();
+
+	// 'double' is needed by 'main' and 'add_one' by 'double', but 'unused' isn't
+	assert_eq!(image.symbols["double"], 0x104);
+	assert_eq!(image.symbols["add_one"], 0x108);
+	assert!(!image.symbols.contains_key("unused"));
+	assert_eq!(
+		image.segments[0].bytes,
+		vec![0, 0, 0x04, 0x01, 0, 0, 0x08, 0x01, 0, 0]
+	);
+	assert!(image.map.contains("helpers.a(double.o)"), "{}", image.map);
+}
+
+#[test]
+fn link_archive_members_aligned()
+{
+	let main = object(&["nop", ".bytes u16, add_one", ".data", ".bytes u8, 1"]);
+	let image = Linker {
+		inputs: vec![LinkInput {
+			name: "main".to_owned(),
+			object: main,
+			base: 0x100,
+		}],
+		archives: vec![("helpers.a".to_owned(), helpers())],
+		..Linker::default()
+	}
+	.link()
+	.unwrap();
+	// 'main' ends at an odd address, so the member's '.text' is aligned after it
+	assert_eq!(image.symbols["add_one"], 0x106);
+	assert_eq!(image.segments[0].bytes, vec![0, 0, 0x06, 0x01, 1]);
+	assert_eq!(image.segments[1].address, 0x106);
+}
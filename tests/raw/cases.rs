@@ -1,6 +1,10 @@
 use duplicate::duplicate_item;
-use scry_asm::{Assemble, Raw};
+use scry_asm::{
+	Label, Raw, Reference, Relocation, RelocationField, SectionFlags, SymbolKind, Visibility,
+	ELF_MACHINE,
+};
 use scry_isa::{AluVariant, Bits, CallVariant, Instruction, Instruction::*, Type};
+use std::collections::HashMap;
 
 trait ByteBlock
 {
@@ -15,6 +19,14 @@ impl ByteBlock for Instruction
 	}
 }
 
+impl ByteBlock for Vec<u8>
+{
+	fn into_bytes(self) -> Vec<u8>
+	{
+		self
+	}
+}
+
 #[duplicate_item(
 	typ; [u8]; [u16]; [u32]; [u64]; [i8]; [i16]; [i32]; [i64]
 )]
@@ -29,11 +41,16 @@ impl ByteBlock for typ
 macro_rules! test_raw {
     (
 		$name:ident { $($asm:literal)* } [$($instructions:expr;)+]
+	) => {
+		test_raw! { $name (Raw::default()) { $($asm)* } [$($instructions;)+] }
+	};
+    (
+		$name:ident ($raw:expr) { $($asm:literal)* } [$($instructions:expr;)+]
 	) => {
         #[test]
         fn $name() {
             // We first assemble the string
-            let assembled = Raw::assemble([
+            let assembled = $raw.assemble_with([
 				$($asm),*
 			].into_iter()).unwrap();
 
@@ -53,11 +70,16 @@ macro_rules! test_raw {
 macro_rules! test_raw_fail {
     (
 		$name:ident { $($asm:literal)* } $err_msg:literal
+	) => {
+		test_raw_fail! { $name (Raw::default()) { $($asm)* } $err_msg }
+	};
+    (
+		$name:ident ($raw:expr) { $($asm:literal)* } $err_msg:literal
 	) => {
         #[test]
         fn $name() {
             // We first assemble the string
-            let assembled = Raw::assemble([
+            let assembled = $raw.assemble_with([
 				$($asm),*
 			].into_iter());
 
@@ -67,6 +89,20 @@ macro_rules! test_raw_fail {
     };
 }
 
+/// Assembler that can load the given in-memory files
+fn with_files(files: &[(&str, &[u8])]) -> Raw
+{
+	Raw {
+		loader: Box::new(
+			files
+				.iter()
+				.map(|(name, contents)| (name.to_string(), contents.to_vec()))
+				.collect::<HashMap<_, _>>(),
+		),
+		..Raw::default()
+	}
+}
+
 test_raw! {
 	independent_instructions
 	{
@@ -352,3 +388,1899 @@ test_raw_fail! {
 	}
 	"Unknown label: cmp_fn_addr"
 }
+
+test_raw! {
+	incbin_whole_file
+	(with_files(&[("table.bin", &[1, 2, 3, 4, 5])]))
+	{
+						".incbin \"table.bin\""
+						"add =>after"
+		"after:"		"nop"
+	}
+	[
+		vec![1u8, 2, 3, 4, 5];
+		Alu(AluVariant::Add, 0.try_into().unwrap());
+		NoOp;
+	]
+}
+
+test_raw! {
+	incbin_offset_and_length
+	(with_files(&[("table.bin", &[1, 2, 3, 4, 5]), ("with space.bin", &[6, 7])]))
+	{
+		"start:"		".incbin \"table.bin\", 1"
+						".incbin \"table.bin\", 1, 2"
+						".incbin \"with space.bin\",0,1"
+						".bytes u8, start=>end"
+		"end:"
+	}
+	[
+		vec![2u8, 3, 4, 5, 2, 3, 6, 8];
+	]
+}
+
+test_raw_fail! {
+	incbin_out_of_bounds
+	(with_files(&[("table.bin", &[1, 2, 3, 4, 5])]))
+	{
+		".incbin \"table.bin\", 2, 4"
+	}
+	"Directive parsing error: 'table.bin' range out of bounds (offset, length, file size): 2, 4, 5"
+}
+
+test_raw_fail! {
+	incbin_range_overflow
+	(with_files(&[("table.bin", &[1, 2, 3, 4, 5])]))
+	{
+		".incbin \"table.bin\", 18446744073709551615, 2"
+	}
+	"Directive parsing error: 'table.bin' range out of bounds (offset, length, file size): \
+	 18446744073709551615, 2, 5"
+}
+
+test_raw_fail! {
+	incbin_offset_beyond_32_bits
+	(with_files(&[("table.bin", &[1, 2, 3, 4, 5])]))
+	{
+		".incbin \"table.bin\", 4294967297, 2"
+	}
+	"Directive parsing error: 'table.bin' range out of bounds (offset, length, file size): \
+	 4294967297, 2, 5"
+}
+
+test_raw_fail! {
+	incbin_missing_file
+	(with_files(&[]))
+	{
+		".incbin \"table.bin\""
+	}
+	"Directive parsing error: Failed to load 'table.bin': File not found"
+}
+
+test_raw! {
+	macro_with_parameters
+	{
+		".macro add_twice(to, extra=0)"
+						"add =>\\to"
+						"add =>\\to"
+						".bytes u16, \\extra"
+		".endm"
+						"add_twice(target)"
+						"add_twice(extra=7, to=target)"
+		"target:"		"nop"
+	}
+	[
+		Alu(AluVariant::Add, 5.try_into().unwrap());
+		Alu(AluVariant::Add, 4.try_into().unwrap());
+		0u16;
+		Alu(AluVariant::Add, 2.try_into().unwrap());
+		Alu(AluVariant::Add, 1.try_into().unwrap());
+		7u16;
+		NoOp;
+	]
+}
+
+test_raw! {
+	macro_unique_labels
+	{
+		".macro over_nop"
+						"add =>after\\@"
+						"nop"
+		"after\\@:"
+		".endm"
+						"over_nop"
+						"over_nop()"
+	}
+	[
+		Alu(AluVariant::Add, 1.try_into().unwrap());
+		NoOp;
+		Alu(AluVariant::Add, 1.try_into().unwrap());
+		NoOp;
+	]
+}
+
+test_raw_fail! {
+	macro_missing_argument
+	{
+		".macro fan(to, from)"	"dup =>\\to, =>\\from"	".endm"
+		"fan(1)"
+	}
+	"Missing argument 'from' for macro 'fan'"
+}
+
+test_raw_fail! {
+	macro_label_defined_twice
+	{
+		".macro twice"	"lab:"	"nop"	".endm"
+		"twice"
+		"twice"
+	}
+	"'lab' defined twice\nIn expansion of macro 'twice'"
+}
+
+test_raw_fail! {
+	macro_expansion_backtrace
+	{
+		".macro load(sym)"	"const u8, \\sym"	".endm"
+		".macro setup"		"nop"	"load(cmp_fn_addr)"	".endm"
+		"setup"
+	}
+	"Unknown label: cmp_fn_addr\nIn expansion of macro 'load'\nIn expansion of macro 'setup'"
+}
+
+test_raw_fail! {
+	macro_named_like_label
+	{
+		".macro target"	"nop"	".endm"
+		"add =>target"
+		"target:"		"nop"
+	}
+	"Label 'target' named like a macro"
+}
+
+test_raw_fail! {
+	label_named_like_macro
+	{
+		"target:"		"nop"
+		".macro target"	"nop"	".endm"
+	}
+	"Macro 'target' named like a label"
+}
+
+test_raw! {
+	rept_block
+	{
+		".rept 3"
+						"nop"
+		".endr"
+		".rept 0"
+						"add =>4"
+		".endr"
+	}
+	[
+		NoOp;
+		NoOp;
+		NoOp;
+	]
+}
+
+test_raw! {
+	rept_with_index
+	{
+		".rept 3, i"	".bytes u8, \\i"	".endr"
+	}
+	[
+		0u8;
+		1u8;
+		2u8;
+	]
+}
+
+test_raw! {
+	irp_block
+	{
+		".irp to, first, second"
+						"add =>\\to"
+		".endr"
+		"first:"		"nop"
+		"second:"		"nop"
+	}
+	[
+		Alu(AluVariant::Add, 1.try_into().unwrap());
+		Alu(AluVariant::Add, 1.try_into().unwrap());
+		NoOp;
+		NoOp;
+	]
+}
+
+test_raw! {
+	nested_repetitions
+	{
+		".rept 2, i"
+			".irp val, 7, 9"
+						".bytes u8, \\i"
+						".bytes u8, \\val"
+			".endr"
+		".endr"
+	}
+	[
+		0u8; 7u8;
+		0u8; 9u8;
+		1u8; 7u8;
+		1u8; 9u8;
+	]
+}
+
+test_raw_fail! {
+	endr_without_rept
+	{
+		"nop"
+		".endr"
+	}
+	"'.endr' without '.rept' or '.irp'"
+}
+
+test_raw_fail! {
+	rept_count_too_large
+	{
+		".rept 1 << 40"
+			"nop"
+		".endr"
+	}
+	"Repetitions too large (maximum 1048576 tokens)"
+}
+
+test_raw_fail! {
+	nested_repetitions_too_large
+	{
+		".rept 1024"
+			".rept 1025"
+				"nop"
+			".endr"
+		".endr"
+	}
+	"Repetitions too large (maximum 1048576 tokens)"
+}
+
+/// Assembler with the given constants defined
+fn with_defines(defines: &[(&str, i128)]) -> Raw
+{
+	Raw {
+		defines: defines
+			.iter()
+			.map(|(name, value)| (name.to_string(), *value))
+			.collect(),
+		..Raw::default()
+	}
+}
+
+test_raw! {
+	if_true_and_false
+	{
+		".if 2 * 3 == 6"
+			".bytes u8, 1"
+		".endif"
+		".if 1 > 2"
+			".bytes u8, 2"
+		".endif"
+		".bytes u8, 3"
+	}
+	[
+		1u8; 3u8;
+	]
+}
+
+test_raw! {
+	if_else
+	(with_defines(&[("VERSION", 2)]))
+	{
+		".if VERSION < 2"
+			".bytes u8, 1"
+		".else"
+			".bytes u8, 2"
+		".endif"
+		".if VERSION >= 2 && !(VERSION % 2)"
+			".bytes u8, 3"
+		".else"
+			".bytes u8, 4"
+		".endif"
+	}
+	[
+		2u8; 3u8;
+	]
+}
+
+test_raw! {
+	nested_if
+	{
+		".if 0"
+			".if 1"
+				".bytes u8, 1"
+			".else"
+				".bytes u8, 2"
+			".endif"
+		".else"
+			".if 1"
+				".bytes u8, 3"
+			".else"
+				".bytes u8, 4"
+			".endif"
+		".endif"
+	}
+	[
+		3u8;
+	]
+}
+
+test_raw! {
+	ifdef_define_and_label
+	(with_defines(&[("DEBUG", 0)]))
+	{
+		"start:"
+		".ifdef DEBUG"
+			".bytes u8, 1"
+		".endif"
+		".ifdef start"
+			".bytes u8, 2"
+		".endif"
+		".ifndef end"
+			".bytes u8, 3"
+		".endif"
+		"end:"
+	}
+	[
+		1u8; 2u8; 3u8;
+	]
+}
+
+test_raw! {
+	ifdef_ignores_labels_in_skipped_blocks
+	{
+		".if 0"
+			"skipped:"
+		".endif"
+		".ifdef skipped"
+			".bytes u8, 1"
+		".else"
+			".bytes u8, 2"
+		".endif"
+	}
+	[
+		2u8;
+	]
+}
+
+test_raw! {
+	rept_count_expression
+	(with_defines(&[("COUNT", 1)]))
+	{
+		".rept COUNT + 1"
+			"nop"
+		".endr"
+	}
+	[
+		NoOp;
+		NoOp;
+	]
+}
+
+test_raw_fail! {
+	if_without_endif
+	{
+		".if 1"
+			"nop"
+	}
+	"'.if' without '.endif'"
+}
+
+test_raw_fail! {
+	if_unknown_constant
+	{
+		".if MISSING"
+		".endif"
+	}
+	"Unknown constant: MISSING"
+}
+
+test_raw! {
+	assert_holds
+	{
+		"start:"
+			".bytes u8, 1"
+			".bytes u8, 2"
+		"end:"
+		".assert end - start == 2, \"table must be 2 bytes\""
+		".assert later > start"
+		"later:"
+	}
+	[
+		1u8; 2u8;
+	]
+}
+
+test_raw_fail! {
+	assert_fails_with_message
+	{
+		"start:"
+			".bytes u16, 1"
+		"end:"
+		".assert end - start <= 1, \"table too large\""
+	}
+	"Assertion failed: table too large"
+}
+
+test_raw_fail! {
+	assert_fails_without_message
+	{
+		"start:"
+			"nop"
+		"end:"
+		".assert end - start == 4"
+	}
+	"Assertion failed: ((end - start) == 4)"
+}
+
+test_raw_fail! {
+	error_directive
+	(with_defines(&[("TARGET", 3)]))
+	{
+		".if TARGET > 2"
+			".error \"unsupported target\""
+		".endif"
+	}
+	"Error: unsupported target"
+}
+
+#[test]
+fn warning_directive()
+{
+	let object = Raw::default()
+		.assemble_object(
+			[
+				".ifndef FAST",
+				".warning \"using slow path\"",
+				".endif",
+				"nop",
+				".if 0",
+				".warning \"skipped\"",
+				".endif",
+			]
+			.into_iter(),
+		)
+		.unwrap();
+	assert_eq!(object.bytes(), NoOp.into_bytes());
+	assert_eq!(object.warnings, vec!["Warning: using slow path".to_owned()]);
+}
+
+#[test]
+fn sections_are_assembled_separately()
+{
+	let object = Raw::default()
+		.assemble_object(
+			[
+				".text",
+				"start:",
+				"nop",
+				".data",
+				"value:",
+				".bytes u8, 7",
+				".text",
+				"nop",
+				".bss",
+				"buffer:",
+				".bytes u16, 0",
+				".section .rodata",
+				"table:",
+				".bytes u8, 3",
+			]
+			.into_iter(),
+		)
+		.unwrap();
+
+	let names: Vec<_> = object.sections.iter().map(|s| s.name.as_str()).collect();
+	assert_eq!(names, [".text", ".data", ".bss", ".rodata"]);
+
+	let text = object.section(".text").unwrap();
+	assert_eq!(text.bytes, [NoOp.into_bytes(), NoOp.into_bytes()].concat());
+	assert_eq!(text.size, 4);
+	assert_eq!(text.flags, SectionFlags::default_for(".text"));
+	assert!(text.flags.execute && !text.flags.write);
+
+	let bss = object.section(".bss").unwrap();
+	assert!(bss.bytes.is_empty());
+	assert_eq!(bss.size, 2);
+	assert!(bss.flags.no_bits && bss.flags.write);
+
+	assert_eq!(
+		object.section(".rodata").unwrap().flags,
+		SectionFlags::default()
+	);
+
+	assert_eq!(
+		object.label("buffer"),
+		Some(&Label {
+			name: "buffer".to_owned(),
+			section: 2,
+			offset: 0,
+			visibility: Visibility::Local,
+			kind: SymbolKind::Unknown,
+			size: None,
+			file: None,
+		})
+	);
+	assert_eq!(object.label("value").unwrap().section, 1);
+	assert_eq!(object.bytes(), vec![0, 0, 0, 0, 7, 0, 0, 3]);
+}
+
+test_raw! {
+	sections_laid_out_in_order
+	{
+		".data"
+		"first:"
+			".bytes u8, 1"
+		".text"
+			".bytes u8, first"
+			".bytes u8, second"
+		".section .data"
+		"second:"
+			".bytes u8, 2"
+	}
+	[
+		// '.text' always comes first
+		2u8; 3u8; 1u8; 2u8;
+	]
+}
+
+test_raw! {
+	section_with_flags
+	{
+		".section .fast, \"wx\""
+			"nop"
+		".section .fast"
+			"nop"
+	}
+	[
+		NoOp;
+		NoOp;
+	]
+}
+
+test_raw_fail! {
+	section_flags_mismatch
+	{
+		".section .fast, \"x\""
+		".section .fast, \"w\""
+	}
+	"Section '.fast' redeclared with different flags"
+}
+
+test_raw_fail! {
+	data_in_bss
+	{
+		".bss"
+			".bytes u8, 1"
+	}
+	"Non-zero data in section without contents: .bss"
+}
+
+#[test]
+fn symbol_table()
+{
+	let object = Raw::default()
+		.assemble_object(
+			[
+				".global main, helper",
+				".extern print, exit",
+				".local internal",
+				"main:",
+				"add =>print",
+				"internal:",
+				"nop",
+				"helper:",
+				".bytes u8, exit",
+				".extern helper",
+			]
+			.into_iter(),
+		)
+		.unwrap();
+
+	let exports: Vec<_> = object.exports().map(|l| l.name.as_str()).collect();
+	assert_eq!(exports, ["main", "helper"]);
+	assert_eq!(
+		object.label("internal").unwrap().visibility,
+		Visibility::Local
+	);
+	assert_eq!(object.imports, ["print", "exit"]);
+	// Imports are assembled as if directly after the referring instruction
+	assert_eq!(
+		object.bytes(),
+		[
+			Alu(AluVariant::Add, 0.try_into().unwrap()).into_bytes(),
+			NoOp.into_bytes(),
+			vec![6],
+		]
+		.concat()
+	);
+}
+
+test_raw_fail! {
+	global_not_defined
+	{
+		".global main"
+		"nop"
+	}
+	"Symbol 'main' declared global but not defined"
+}
+
+test_raw_fail! {
+	global_and_local
+	{
+		".global main"
+		".local main"
+		"main:"
+	}
+	"Symbol 'main' declared both global and local"
+}
+
+#[test]
+fn symbol_metadata()
+{
+	let object = Raw::default()
+		.assemble_object(
+			[
+				".func main",
+				"nop",
+				"nop",
+				".endfunc",
+				".data",
+				".type table, @object",
+				".size table, table_end - table",
+				"table:",
+				".bytes u8, 1",
+				".bytes u8, 2",
+				".bytes u8, 3",
+				"table_end:",
+				".type helper, function",
+				".text",
+				"helper:",
+				"nop",
+			]
+			.into_iter(),
+		)
+		.unwrap();
+
+	let main = object.label("main").unwrap();
+	assert_eq!(
+		(main.kind, main.size, main.offset),
+		(SymbolKind::Function, Some(4), 0)
+	);
+	let table = object.label("table").unwrap();
+	assert_eq!((table.kind, table.size), (SymbolKind::Object, Some(3)));
+	let helper = object.label("helper").unwrap();
+	assert_eq!((helper.kind, helper.size), (SymbolKind::Function, None));
+	assert_eq!(object.label("table_end").unwrap().kind, SymbolKind::Unknown);
+}
+
+test_raw_fail! {
+	func_without_endfunc
+	{
+		".func main"
+			"nop"
+	}
+	"'.func' of 'main' without '.endfunc'"
+}
+
+test_raw_fail! {
+	endfunc_without_func
+	{
+		"nop"
+		".endfunc"
+	}
+	"'.endfunc' without '.func'"
+}
+
+test_raw_fail! {
+	func_label_defined_twice
+	{
+		"main:"
+		".func main"
+		".endfunc"
+	}
+	"'main' defined twice"
+}
+
+test_raw! {
+	crc32_directive
+	{
+		"start:"
+			".bytes u8, 49" ".bytes u8, 50" ".bytes u8, 51"
+			".bytes u8, 52" ".bytes u8, 53" ".bytes u8, 54"
+			".bytes u8, 55" ".bytes u8, 56" ".bytes u8, 57"
+		"end:"
+		".crc32 start, end"
+	}
+	[
+		49u8; 50u8; 51u8; 52u8; 53u8; 54u8; 55u8; 56u8; 57u8;
+		0xCBF43926u32;
+	]
+}
+
+test_raw! {
+	crc32_big_endian
+	{
+		".endian big"
+		"start:"
+			".bytes u8, 49" ".bytes u8, 50" ".bytes u8, 51"
+			".bytes u8, 52" ".bytes u8, 53" ".bytes u8, 54"
+			".bytes u8, 55" ".bytes u8, 56" ".bytes u8, 57"
+		"end:"
+		".crc32 start, end"
+	}
+	[
+		49u8; 50u8; 51u8; 52u8; 53u8; 54u8; 55u8; 56u8; 57u8;
+		0xCBF43926u32.to_be_bytes().to_vec();
+	]
+}
+
+test_raw! {
+	crc32_covering_later_checksum
+	{
+		".crc32 data, end"
+		"data:"
+			".bytes u8, 7"
+		"sum:"
+			".sum8 data, sum"
+		"end:"
+	}
+	[
+		// The CRC covers the final value of the '.sum8'
+		0x90FC119Bu32;
+		7u8;
+		7u8;
+	]
+}
+
+test_raw_fail! {
+	checksums_covering_each_other
+	{
+		"first:"
+			".sum8 second, end"
+		"second:"
+			".sum8 first, second"
+		"end:"
+	}
+	"Checksums cover each other"
+}
+
+test_raw! {
+	sum8_includes_own_location
+	{
+		"start:"
+			".bytes u8, 200"
+			".sum8 start, end"
+			".bytes u8, 100"
+		"end:"
+	}
+	[
+		200u8; 44u8; 100u8;
+	]
+}
+
+test_raw! {
+	checksums_see_later_bytes_and_sections
+	{
+		"first:"
+		".sum8 data, data_end"
+		".sum8 first, data_end"
+		".data"
+		"data:"
+			".bytes u8, 3"
+			".bytes u8, 4"
+		"data_end:"
+	}
+	[
+		7u8; 14u8; 3u8; 4u8;
+	]
+}
+
+test_raw_fail! {
+	checksum_out_of_bounds
+	{
+		"start:"
+		".sum8 start, 10"
+	}
+	"Checksum range out of bounds (start, end, size): 0, 10, 1"
+}
+
+test_raw_fail! {
+	checksum_in_relocatable_object
+	(Raw {
+		relocatable: true,
+		..Raw::default()
+	})
+	{
+		"start:"
+		"nop"
+		".sum8 start, end"
+		"end:"
+	}
+	"Checksum in relocatable object"
+}
+
+test_raw! {
+	endian_directive
+	{
+		".bytes u16, 258"
+		".endian big"
+			".bytes u16, 258"
+			".bytes i32, -2"
+			".bytes u8, 3"
+			"nop"
+		".endian little"
+			".bytes u16, 258"
+	}
+	[
+		vec![2u8, 1];
+		vec![1u8, 2];
+		vec![0xFFu8, 0xFF, 0xFF, 0xFE];
+		3u8;
+		NoOp;
+		vec![2u8, 1];
+	]
+}
+
+test_raw_fail! {
+	endian_unknown
+	{
+		".endian middle"
+	}
+	"Unknown endianness: middle"
+}
+
+test_raw! {
+	expression_operands
+	(with_defines(&[("SCALE", 3)]))
+	{
+		"table:"
+			".bytes u8, 1"
+			".bytes u8, 2"
+		"table_end:"
+		".bytes u8, {table_end - table + 2}"
+		".bytes u16, {(1 << 10) | SCALE * -(-2) ^ ~0 & 3}"
+		"add =>{target + 2}"
+		"target:"
+			"nop"
+			"nop"
+	}
+	[
+		1u8; 2u8; 4u8;
+		1029u16;
+		Alu(AluVariant::Add, 1.try_into().unwrap());
+		NoOp;
+		NoOp;
+	]
+}
+
+test_raw_fail! {
+	expression_division_by_zero
+	{
+		"start:"
+		".bytes u8, {start / (start - start)}"
+	}
+	"Directive parsing error: Division by zero: (start / (start - start))"
+}
+
+test_raw_fail! {
+	expression_overflow
+	{
+		".bytes i32, {1 << 127}"
+	}
+	"Directive parsing error: Overflow: (1 << 127)"
+}
+
+test_raw! {
+	expression_wide_values
+	{
+		".bytes u64, {0x8000000000000000}"
+		".bytes u128, {(1 << 100) + 0x10000000000000000}"
+		".bytes i128, {-0x10000000000000000}"
+	}
+	[
+		0x8000000000000000u64;
+		((1u128 << 100) + (1 << 64)).to_le_bytes().to_vec();
+		(-(1i128 << 64)).to_le_bytes().to_vec();
+	]
+}
+
+test_raw_fail! {
+	expression_negative_unsigned
+	{
+		"a:"
+		"nop"
+		"b:"
+		".bytes u8, {a - b}"
+	}
+	"Directive parsing error: Bytes value out of bounds (actual, minimum, maximum): -2, 0, 255"
+}
+
+test_raw_fail! {
+	expression_number_out_of_range
+	{
+		".bytes u128, {0x80000000000000000000000000000000}"
+	}
+	"Number out of range: 170141183460469231731687303715884105728"
+}
+
+test_raw_fail! {
+	expression_unknown_label
+	{
+		"add =>{missing + 2}"
+	}
+	"Unknown label: missing"
+}
+
+test_raw_fail! {
+	expression_unclosed
+	{
+		".bytes u8, {1 + 2"
+	}
+	"Expected '}' after expression: "
+}
+
+test_raw! {
+	location_counter
+	{
+		"nop"
+		"table:"
+			".bytes u8, {.}"
+			".bytes u8, {target - .}"
+			".bytes u8, {$ - table}"
+			".bytes u8, ."
+			".bytes u8, $"
+			".bytes u8, table=>."
+		".assert . == 8"
+		"nop"
+		"target:"
+	}
+	[
+		NoOp;
+		2u8; 7u8; 2u8; 5u8; 6u8; 5u8;
+		NoOp;
+	]
+}
+
+#[test]
+fn location_counter_in_size()
+{
+	let object = Raw::default()
+		.assemble_object(
+			[
+				".data",
+				"table:",
+				".bytes u16, 1",
+				".bytes u16, 2",
+				".size table, . - table",
+			]
+			.into_iter(),
+		)
+		.unwrap();
+	assert_eq!(object.label("table").unwrap().size, Some(4));
+}
+
+test_raw! {
+	split_values
+	{
+		".bytes u8, {%lo8(target)}"
+		".bytes u8, {%hi8(target)}"
+		".bytes u8, {%bits(1000, 0, 4)}"
+		".bytes u8, {%bits(1000, 5, 9)}"
+		".bytes u8, {%lo8(-2)}"
+		".bytes u8, {%lo8(%hi8(515)) + %lo8(515)}"
+		"target:"
+	}
+	[
+		6u8; 0u8;
+		8u8; 31u8;
+		254u8;
+		5u8;
+	]
+}
+
+test_raw_fail! {
+	split_value_not_reconstructed
+	{
+		".bytes u8, {%lo8(70000)}"
+		".bytes u8, {%hi8(70000)}"
+	}
+	"Parts of '70000' don't reconstruct its value: 70000"
+}
+
+test_raw_fail! {
+	split_value_gap
+	{
+		".bytes u8, {%lo8(-300)}"
+		".bytes u8, {%bits(-300, 9, 15)}"
+	}
+	"Parts of '-300' don't reconstruct its value: -300"
+}
+
+test_raw_fail! {
+	split_invalid_bit_range
+	{
+		".bytes u8, {%bits(1, 5, 2)}"
+	}
+	"Invalid bit range: %bits(1,"
+}
+
+test_raw! {
+	numeric_literals
+	{
+		".bytes u16, 0x1F_FF"
+		".bytes u8, 0b1010_0101"
+		".bytes u8, 0o17"
+		".bytes u32, 1_000_000"
+		".bytes i8, -0x10"
+		".bytes u8, 'A'"
+		".bytes u8, '\\n'"
+		".bytes u8, ' '"
+		".bytes u8, ';'"
+		".bytes u8, '\\''"
+		".bytes u8, {0xF0 | 0b1111}"
+		"label_0x1:"
+		".bytes u8, {label_0x1 + 'a' - 'a'}"
+	}
+	[
+		0x1FFFu16;
+		0b1010_0101u8;
+		0o17u8;
+		1_000_000u32;
+		-16i8;
+		65u8;
+		10u8;
+		32u8;
+		59u8;
+		39u8;
+		255u8;
+		15u8;
+	]
+}
+
+test_raw! {
+	numeric_literals_in_directives
+	(with_files(&[("table.bin", &[1, 2, 3, 4, 5])]))
+	{
+		".rept 0b10"
+			".incbin \"table.bin\", 0x1, 0o2"
+		".endr"
+		".if 'a' == 0x61"
+			".bytes u8, 0xff"
+		".endif"
+	}
+	[
+		2u8; 3u8; 2u8; 3u8; 255u8;
+	]
+}
+
+test_raw_fail! {
+	invalid_hex_literal
+	{
+		".bytes u8, 0x1G"
+	}
+	"Invalid number: 0x1G"
+}
+
+test_raw_fail! {
+	invalid_char_literal
+	{
+		".bytes u8, 'ab'"
+	}
+	"Invalid character literal: 'ab'"
+}
+
+test_raw! {
+	local_labels
+	{
+		"first:"
+			"add =>.done"
+			"nop"
+		".done:"
+			".bytes u8, {.done - first}"
+		"second:"
+			"add =>.done"
+		".done:"
+			".bytes u8, {first.done}"
+			".bytes u8, {second.done}"
+			".bytes u8, {.done}"
+	}
+	[
+		Alu(AluVariant::Add, 1.try_into().unwrap());
+		NoOp;
+		4u8;
+		Alu(AluVariant::Add, 0.try_into().unwrap());
+		4u8; 7u8; 7u8;
+	]
+}
+
+#[test]
+fn local_labels_in_symbol_table()
+{
+	let object = Raw::default()
+		.assemble_object(
+			[
+				".loop:",
+				"nop",
+				"func:",
+				".loop:",
+				"nop",
+				".global func.loop",
+			]
+			.into_iter(),
+		)
+		.unwrap();
+	let names: Vec<_> = object.labels.iter().map(|l| l.name.as_str()).collect();
+	assert_eq!(names, [".loop", "func", "func.loop"]);
+	assert_eq!(object.label("func.loop").unwrap().offset, 2);
+	assert_eq!(object.exports().next().unwrap().name, "func.loop");
+}
+
+test_raw_fail! {
+	local_label_defined_twice
+	{
+		"func:"
+		".loop:"
+		".loop:"
+	}
+	"'func.loop' defined twice"
+}
+
+test_raw_fail! {
+	local_label_out_of_scope
+	{
+		"first:"
+		".done:"
+		"second:"
+			".bytes u8, {.done}"
+	}
+	"Directive parsing error: Unknown label: .done"
+}
+
+test_raw! {
+	numeric_labels
+	{
+		"1:"
+			"add =>1f"
+			"nop"
+		"1:"
+			".bytes u8, 1b"
+			".bytes u8, {1f - 1b}"
+		"1 :"
+			".bytes u8, 1b"
+		"0:"
+			".bytes u8, 0b"
+		"func:"
+		".local_:"
+			".bytes u8, {.local_}"
+	}
+	[
+		Alu(AluVariant::Add, 1.try_into().unwrap());
+		NoOp;
+		4u8; 2u8;
+		6u8;
+		7u8;
+		8u8;
+	]
+}
+
+test_raw! {
+	numeric_labels_jmp_forward
+	{
+				"add =>1f=>2f"
+				"jmp 2f, 1f"
+	 "1:"
+				"add =>0"
+	 "2:"		"sub =>12"
+	}
+	[
+		Alu(AluVariant::Add, 1.try_into().unwrap());
+		Jump(1.try_into().unwrap(),0.try_into().unwrap());
+		Alu(AluVariant::Add, 0.try_into().unwrap());
+		Alu(AluVariant::Sub, 12.try_into().unwrap());
+	]
+}
+
+test_raw! {
+	numeric_labels_jmp_backward
+	{
+					"add =>2f=>1f=>3f"
+		"1:"		"jmp 1b, 2f"
+					"nop"
+		"3:"		"sub =>3"
+					"nop"
+					"sub =>0"
+		"2:"
+	}
+	[
+		Alu(AluVariant::Add, 7.try_into().unwrap());
+		Jump(0.try_into().unwrap(),4.try_into().unwrap());
+		NoOp;
+		Alu(AluVariant::Sub, 3.try_into().unwrap());
+		NoOp;
+		Alu(AluVariant::Sub, 0.try_into().unwrap());
+	]
+}
+
+test_raw! {
+	numeric_label_number_stays_literal
+	{
+		"nop"
+		"2:"
+			".bytes u8, 2"
+			".bytes u8, {2 + 1}"
+	}
+	[
+		NoOp;
+		2u8; 3u8;
+	]
+}
+
+test_raw_fail! {
+	numeric_label_missing_forward
+	{
+		"1:"
+		".bytes u8, {1f}"
+	}
+	"Directive parsing error: Unknown label: 1f"
+}
+
+test_raw! {
+	origin_relocates_addresses
+	(Raw { origin: 0x100, ..Raw::default() })
+	{
+		"start:"
+			"add =>target"
+			".bytes u16, start"
+			".bytes u16, {.}"
+			".bytes u8, {target - start}"
+			".crc32 start, target"
+		"target:"
+			"nop"
+		".assert target == 0x10B"
+	}
+	[
+		Alu(AluVariant::Add, 4.try_into().unwrap());
+		0x100u16;
+		0x104u16;
+		11u8;
+		0x789985BFu32;
+		NoOp;
+	]
+}
+
+test_raw! {
+	addresses_beyond_32_bits
+	(Raw { origin: 0x1_2345_6789_0000, ..Raw::default() })
+	{
+		"start:"
+			".bytes u64, start"
+			".bytes i64, end"
+			".bytes u8, {end - start}"
+			"add =>end"
+		"end:"
+	}
+	[
+		0x1_2345_6789_0000u64;
+		0x1_2345_6789_0013i64;
+		19u8;
+		Alu(AluVariant::Add, 0.try_into().unwrap());
+	]
+}
+
+test_raw_fail! {
+	address_too_large_for_data
+	(Raw { origin: 0x1_0000_0000, ..Raw::default() })
+	{
+		"start:"
+		".bytes u32, start"
+	}
+	"Directive parsing error: Bytes value out of bounds (actual, minimum, maximum): 4294967296, \
+	 0, 4294967295"
+}
+
+test_raw! {
+	addresses_beyond_63_bits
+	(Raw { origin: 0xFFFF_FFFF_0000_0000, ..Raw::default() })
+	{
+		"start:"
+			".bytes u64, start"
+			".bytes u64, end"
+			".bytes u8, {end - start}"
+			"add =>end"
+		"end:"
+	}
+	[
+		0xFFFF_FFFF_0000_0000u64;
+		0xFFFF_FFFF_0000_0013u64;
+		19u8;
+		Alu(AluVariant::Add, 0.try_into().unwrap());
+	]
+}
+
+test_raw_fail! {
+	origin_near_end_of_addresses
+	(Raw { origin: u64::MAX - 2, ..Raw::default() })
+	{
+		"nop"
+		"nop"
+	}
+	"Origin out of range: 18446744073709551613"
+}
+
+#[test]
+fn assemble_multiple_files()
+{
+	let object = Raw::default()
+		.assemble_files(&[
+			(
+				"main.asm",
+				".global main\n.extern helper\nmain:\nloop:\n.bytes u8, {helper - main}\n.bytes \
+				 u8, {loop}\n.data\n.bytes u8, 1",
+			),
+			(
+				"helper.asm",
+				".global helper\nloop:\n.bytes u8, {loop}\nhelper:\n.bytes u8, {helper}",
+			),
+		])
+		.unwrap();
+	// Each file starts in '.text', so 'helper.asm' comes directly after 'main.asm'
+	assert_eq!(object.bytes(), vec![3, 0, 2, 3, 1]);
+	assert!(object.imports.is_empty());
+	assert_eq!(
+		object
+			.exports()
+			.map(|label| label.name.as_str())
+			.collect::<Vec<_>>(),
+		vec!["main", "helper"]
+	);
+	let files: Vec<_> = object
+		.labels
+		.iter()
+		.map(|label| (label.name.as_str(), label.file.as_deref().unwrap()))
+		.collect();
+	assert_eq!(
+		files,
+		vec![
+			("main", "main.asm"),
+			("loop", "main.asm"),
+			("loop", "helper.asm"),
+			("helper", "helper.asm"),
+		]
+	);
+}
+
+#[test]
+fn private_label_not_visible_in_other_file()
+{
+	assert_eq!(
+		Raw::default().assemble_files(&[
+			("a.asm", ".bytes u8, {value}"),
+			("b.asm", "value:\n.bytes u8, 0"),
+		]),
+		Err("Directive parsing error: Unknown label: value\nIn file 'a.asm'".to_owned())
+	);
+}
+
+#[test]
+fn private_label_named_like_keyword()
+{
+	for name in ["add", "u8", "big", "little", "function", "object"]
+	{
+		assert_eq!(
+			Raw::default().assemble_files(&[
+				("a.asm", "nop"),
+				("b.asm", &format!("{}:\nadd =>{}\n.bytes u8, 0", name, name)),
+			]),
+			Err(format!(
+				"Private label named like a keyword: {}\nIn file 'b.asm'",
+				name
+			))
+		);
+	}
+}
+
+#[test]
+fn private_label_named_like_section()
+{
+	assert_eq!(
+		Raw::default().assemble_files(&[
+			("a.asm", "nop"),
+			("b.asm", "rom:\nnop\n.section rom\n.bytes u8, 0"),
+		]),
+		Err("Label named like a section: rom\nIn file 'b.asm'".to_owned())
+	);
+}
+
+test_raw_fail! {
+	local_label_named_like_section
+	{
+		"func:"		"nop"
+		".rodata:"	"nop"
+		".section .rodata"
+					".bytes u8, 0"
+	}
+	"Label named like a section: .rodata"
+}
+
+#[test]
+fn global_defined_in_two_files()
+{
+	assert_eq!(
+		Raw::default().assemble_files(&[
+			("a.asm", ".global start\nstart:\nnop"),
+			("b.asm", "nop\n.global start\nstart:\nnop"),
+		]),
+		Err("'start' defined twice, first in 'a.asm'\nIn file 'b.asm'".to_owned())
+	);
+}
+
+#[test]
+fn macros_private_to_file()
+{
+	assert_eq!(
+		Raw::default()
+			.assemble_files(&[
+				("a.asm", ".macro one\n.bytes u8, 1\n.endm\none"),
+				("b.asm", ".macro one\n.bytes u8, 2\n.endm\none"),
+			])
+			.map(|object| object.bytes()),
+		Ok(vec![1, 2])
+	);
+}
+
+test_raw_fail! {
+	extern_needs_linking
+	{
+		".extern print"
+		"add =>print"
+	}
+	"Unknown label: print"
+}
+
+test_raw_fail! {
+	relocatable_needs_linking
+	(Raw {
+		relocatable: true,
+		..Raw::default()
+	})
+	{
+		"start:"
+		".bytes u16, start"
+	}
+	"Relocatable object can't be assembled without linking"
+}
+
+#[test]
+fn relocations_for_undefined_symbols()
+{
+	let raw = Raw {
+		relocatable: true,
+		..Raw::default()
+	};
+	let object = raw
+		.assemble_object(["nop", "add =>print", ".bytes u16, table", "print:"].into_iter())
+		.unwrap();
+	assert_eq!(object.imports, vec!["table".to_owned()]);
+	assert_eq!(
+		object.relocations,
+		vec![Relocation {
+			section: 0,
+			offset: 4,
+			field: RelocationField::Bytes {
+				size: 2,
+				big_endian: false,
+			},
+			reference: Reference::Address("table".to_owned()),
+			targets: vec![None],
+			source: Vec::new(),
+			resolved: Vec::new(),
+		}]
+	);
+	// Assembled as if 'table' was directly after the data
+	assert_eq!(object.bytes()[4..], [6, 0]);
+}
+
+#[test]
+fn relocations_for_instructions()
+{
+	let object = Raw::default()
+		.assemble_object([".extern print", "nop", "add =>print"].into_iter())
+		.unwrap();
+	assert_eq!(object.imports, vec!["print".to_owned()]);
+	let relocation = &object.relocations[0];
+	assert_eq!(object.relocations.len(), 1);
+	assert_eq!((relocation.section, relocation.offset), (0, 2));
+	assert_eq!(relocation.field, RelocationField::OutputOffset);
+	assert_eq!(
+		relocation.reference,
+		Reference::DistanceCurrent("print".to_owned())
+	);
+	assert_eq!(relocation.source.concat(), "add=>print");
+}
+
+#[test]
+fn relocations_name_symbols_as_in_source()
+{
+	let raw = Raw {
+		relocatable: true,
+		..Raw::default()
+	};
+	let object = raw
+		.assemble_object(
+			[
+				"func:",
+				"const u8, .value",
+				"call 1f",
+				".data",
+				".value:",
+				".bytes u8, 2",
+				"1:",
+				".bytes u8, 1b",
+			]
+			.into_iter(),
+		)
+		.unwrap();
+	let names: Vec<_> = object
+		.relocations
+		.iter()
+		.map(|relocation| (relocation.reference.clone(), relocation.source.concat()))
+		.collect();
+	assert_eq!(
+		names,
+		[
+			(
+				Reference::Address("func.value".to_owned()),
+				"constu8,func.value".to_owned()
+			),
+			(
+				Reference::DistanceCurrent("1f".to_owned()),
+				"call1f".to_owned()
+			),
+			(Reference::Address("1b".to_owned()), String::new()),
+		]
+	);
+}
+
+#[test]
+fn relocation_fields()
+{
+	let object = Raw::default()
+		.assemble_object(
+			[
+				".extern print, value",
+				"start:",
+				"add =>print",
+				"const u8, value",
+				"call print",
+				"ret print",
+				"jmp print, end",
+				"jmp start, print",
+				".bytes u16, print",
+				"end:",
+			]
+			.into_iter(),
+		)
+		.unwrap();
+	let fields: Vec<_> = object
+		.relocations
+		.iter()
+		.map(|relocation| (relocation.offset, relocation.field))
+		.collect();
+	assert_eq!(
+		fields,
+		[
+			(0, RelocationField::OutputOffset),
+			(2, RelocationField::ConstImmediate),
+			(4, RelocationField::JumpTarget),
+			(6, RelocationField::JumpLocation),
+			// The field depends on the operand of 'jmp' the reference is in
+			(8, RelocationField::JumpTarget),
+			(10, RelocationField::JumpLocation),
+			(
+				12,
+				RelocationField::Bytes {
+					size: 2,
+					big_endian: false,
+				}
+			),
+		]
+	);
+}
+
+test_raw! {
+	function_section_after_odd_data
+	(Raw {
+		function_sections: true,
+		..Raw::default()
+	})
+	{
+		".data"
+			".bytes u8, 1"
+		".text"
+		".func main"
+			".bytes u16, main"
+		".endfunc"
+	}
+	[
+		// '.text.main' is aligned to instructions
+		1u8;
+		0u8;
+		2u16;
+	]
+}
+
+#[test]
+fn function_sections()
+{
+	let object = Raw {
+		function_sections: true,
+		..Raw::default()
+	}
+	.assemble_object(
+		[
+			".func main",
+			"nop",
+			".endfunc",
+			".func helper",
+			"nop",
+			"nop",
+			".endfunc",
+			"nop",
+		]
+		.into_iter(),
+	)
+	.unwrap();
+	let sections: Vec<_> = object
+		.sections
+		.iter()
+		.map(|s| (s.name.as_str(), s.size))
+		.collect();
+	assert_eq!(
+		sections,
+		[(".text", 2), (".text.main", 2), (".text.helper", 4)]
+	);
+	let helper = object.label("helper").unwrap();
+	assert_eq!(
+		(helper.section, helper.offset, helper.size),
+		(2, 0, Some(4))
+	);
+}
+
+test_raw! {
+	func_scopes_local_labels
+	{
+		".func first"
+		".l:"	".bytes u8, .l"
+		".endfunc"
+		".func second"
+				"nop"
+		".l:"	".bytes u8, .l"
+		".endfunc"
+				".bytes u8, first.l"
+	}
+	[
+		0u8;
+		NoOp;
+		3u8;
+		0u8;
+	]
+}
+
+#[test]
+fn global_local_label_keeps_scope_private()
+{
+	let files = |other| {
+		Raw::default().assemble_files(&[
+			("a.asm", ".global func.loop\nfunc:\nnop\n.loop:\nnop"),
+			("b.asm", other),
+		])
+	};
+	let object = files("nop").unwrap();
+	assert_eq!(
+		object.label("func.loop").unwrap().visibility,
+		Visibility::Global
+	);
+	// Only 'func.loop' is global, not 'func'
+	assert_eq!(
+		files(".bytes u8, {func}"),
+		Err("Directive parsing error: Unknown label: func\nIn file 'b.asm'".to_owned())
+	);
+}
+
+#[test]
+fn private_functions_in_two_files()
+{
+	let object = Raw {
+		function_sections: true,
+		..Raw::default()
+	}
+	.assemble_files(&[
+		(
+			"a.asm",
+			".func helper
+nop
+.endfunc",
+		),
+		(
+			"b.asm",
+			".func helper
+nop
+nop
+.endfunc",
+		),
+	])
+	.unwrap();
+	let sections: Vec<_> = object
+		.sections
+		.iter()
+		.map(|s| (s.name.as_str(), s.size))
+		.collect();
+	assert_eq!(
+		sections,
+		[(".text", 0), (".text.helper", 2), (".text.helper.1", 4)]
+	);
+	let helpers: Vec<_> = object
+		.labels
+		.iter()
+		.map(|label| (label.name.as_str(), label.visibility, label.section))
+		.collect();
+	assert_eq!(
+		helpers,
+		[
+			("helper", Visibility::Local, 1),
+			("helper", Visibility::Local, 2)
+		]
+	);
+}
+
+#[test]
+fn expressions_in_relocatable_object()
+{
+	let raw = Raw {
+		relocatable: true,
+		defines: HashMap::from([("SIZE".to_owned(), 4)]),
+		..Raw::default()
+	};
+	let object = raw
+		.assemble_object([".bytes u8, {SIZE * 2}"].into_iter())
+		.unwrap();
+	assert_eq!(object.bytes(), [8]);
+
+	for (asm, sym) in [
+		(".bytes u16, {table + 2}", "'table'"),
+		(".bytes u8, {%lo8(table)}", "'table'"),
+		(".bytes u8, {.}", "'.'"),
+		(".bytes u8, .", "'.'"),
+		("add =>{table}", "'table'"),
+	]
+	{
+		let err = raw
+			.assemble_object([asm, "table:"].into_iter())
+			.unwrap_err();
+		let expected = format!(
+			"Address of {} used in expression of relocatable object",
+			sym
+		);
+		assert!(err.contains(&expected), "{}", err);
+	}
+}
+
+/// Returns the name, type, offset and size of each section of an ELF object.
+fn elf_sections(elf: &[u8]) -> Vec<(String, u32, usize, usize)>
+{
+	let word = |offset: usize| u32::from_le_bytes(elf[offset..offset + 4].try_into().unwrap());
+	let headers = word(32) as usize;
+	let count = u16::from_le_bytes([elf[48], elf[49]]) as usize;
+	let header = |idx: usize, field: usize| word(headers + idx * 40 + field * 4);
+	let names = header(count - 1, 4) as usize;
+	(0..count)
+		.map(|idx| {
+			let name = &elf[names + header(idx, 0) as usize..];
+			let name = &name[..name.iter().position(|b| *b == 0).unwrap()];
+			(
+				String::from_utf8(name.to_vec()).unwrap(),
+				header(idx, 1),
+				header(idx, 4) as usize,
+				header(idx, 5) as usize,
+			)
+		})
+		.collect()
+}
+
+#[test]
+fn elf_relocatable_object()
+{
+	let raw = Raw {
+		relocatable: true,
+		..Raw::default()
+	};
+	let object = raw
+		.assemble_object(
+			[
+				".global main",
+				"main:",
+				"nop",
+				"add =>print",
+				".data",
+				".bytes u16, main",
+			]
+			.into_iter(),
+		)
+		.unwrap();
+	let elf = object.to_elf().unwrap();
+
+	assert_eq!(elf[..7], *b"\x7fELF\x01\x01\x01");
+	// Relocatable
+	assert_eq!(elf[16..18], [1, 0]);
+	assert_eq!(elf[18..20], ELF_MACHINE.to_le_bytes());
+
+	let sections = elf_sections(&elf);
+	let names: Vec<_> = sections.iter().map(|(name, ..)| name.as_str()).collect();
+	assert_eq!(
+		names,
+		vec![
+			"",
+			".text",
+			".data",
+			".rela.text",
+			".rela.data",
+			".symtab",
+			".strtab",
+			".shstrtab"
+		]
+	);
+	let (_, _, offset, size) = sections[1];
+	assert_eq!(elf[offset..offset + size], object.sections[0].bytes);
+	// The alignment of '.text' and '.data'
+	let alignment = |idx: usize| {
+		let headers = u32::from_le_bytes(elf[32..36].try_into().unwrap()) as usize;
+		elf[headers + idx * 40 + 32]
+	};
+	assert_eq!((alignment(1), alignment(2)), (2, 1));
+
+	// The null symbol, the section symbols, 'main' and 'print'
+	let (_, kind, offset, size) = sections[5];
+	assert_eq!((kind, size), (2, 5 * 16));
+	let (_, _, strtab, _) = sections[6];
+	let symbol = |idx: usize| {
+		let name = &elf[strtab + elf[offset + idx * 16] as usize..];
+		let name = &name[..name.iter().position(|b| *b == 0).unwrap()];
+		// The name, the binding and the section
+		(
+			name.to_vec(),
+			elf[offset + idx * 16 + 12] >> 4,
+			elf[offset + idx * 16 + 14],
+		)
+	};
+	assert_eq!(symbol(3), (b"main".to_vec(), 1, 1));
+	assert_eq!(symbol(4), (b"print".to_vec(), 1, 0));
+
+	// 'add =>print' is an output offset relative to 'print'
+	let (_, kind, offset, size) = sections[3];
+	assert_eq!((kind, size), (4, 12));
+	assert_eq!(elf[offset..offset + 8], [2, 0, 0, 0, 1 | 1 << 3, 4, 0, 0]);
+	// '.bytes u16, main' is the address of '.text', as 'main' is at its start
+	let (_, _, offset, _) = sections[4];
+	assert_eq!(elf[offset..offset + 8], [0, 0, 0, 0, 5 | 1 << 5, 1, 0, 0]);
+}
+
+#[test]
+fn elf_field_out_of_range()
+{
+	let mut object = Raw::default()
+		.assemble_object(["main:", "nop"].into_iter())
+		.unwrap();
+	object.labels[0].offset = u32::MAX as usize + 1;
+	assert_eq!(
+		object.to_elf(),
+		Err("Label offset out of range for ELF: 4294967296".to_owned())
+	);
+}